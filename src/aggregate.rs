@@ -0,0 +1,258 @@
+use crate::metric::{MetricData, MetricType, Series};
+use std::collections::HashMap;
+
+/// The percentiles summarized when none are configured
+pub const DEFAULT_PERCENTILES: [f64; 3] = [50.0, 95.0, 99.0];
+
+/// Statistics computed client side from the values recorded for one series.
+#[derive(Debug, PartialEq)]
+pub struct Summary {
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    pub percentiles: Vec<(f64, f64)>
+}
+
+impl Summary {
+    /// Summarize the values, computing each percentile with the nearest-rank method.
+    ///
+    /// Returns `None` if there are no values to summarize.
+    pub fn new(mut values: Vec<f64>, percentiles: &[f64]) -> Option<Summary> {
+        if values.is_empty() {
+            return None;
+        }
+
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+
+        let count = values.len();
+        let sum: f64 = values.iter().sum();
+
+        let percentiles = percentiles.iter().map(|percentile| {
+            let rank = ((percentile / 100.0) * count as f64).ceil() as usize;
+            (*percentile, values[rank.clamp(1, count) - 1])
+        }).collect();
+
+        Some(Summary {
+            count,
+            sum,
+            min: values[0],
+            max: values[count - 1],
+            mean: sum / count as f64,
+            percentiles
+        })
+    }
+
+    /// The derived series of this summary, as suffixes of the summarized path
+    pub fn series(&self) -> Vec<(String, f64)> {
+        let mut series = vec![
+            ("count".to_owned(), self.count as f64),
+            ("sum".to_owned(), self.sum),
+            ("min".to_owned(), self.min),
+            ("max".to_owned(), self.max),
+            ("mean".to_owned(), self.mean),
+        ];
+
+        series.extend(self.percentiles.iter().map(|(percentile, value)| {
+            (percentile_suffix(*percentile), *value)
+        }));
+
+        series
+    }
+}
+
+/// The series suffix of a percentile, such as `p95` or `p99_9`
+pub fn percentile_suffix(percentile: f64) -> String {
+    format!("p{}", percentile).replace('.', "_")
+}
+
+/// Replaces every value extracted by `value` with a summary of the values recorded for
+/// the same series.
+///
+/// The summary is placed where the first value of the series appeared, and carries the time
+/// the last value was recorded. Records `value` does not extract are left untouched.
+pub fn summarize<I, F>(metrics: I, percentiles: &[f64], value: F) -> Vec<MetricData> where
+    I: IntoIterator<Item=MetricData>,
+    F: Fn(&MetricType) -> Option<f64>
+{
+    let mut ordered: Vec<Result<MetricData, Series>> = Vec::new();
+    let mut series: HashMap<Series, (MetricData, Vec<f64>)> = HashMap::new();
+
+    for metric in metrics {
+        let recorded = match value(metric.metric()) {
+            Some(recorded) => recorded,
+            None => {
+                ordered.push(Ok(metric));
+                continue;
+            }
+        };
+
+        let key = metric.series();
+        match series.get_mut(&key) {
+            Some((latest, values)) => {
+                values.push(recorded);
+                if metric.occurred() >= latest.occurred() {
+                    *latest = metric;
+                }
+            },
+            None => {
+                ordered.push(Err(key.clone()));
+                series.insert(key, (metric, vec![recorded]));
+            }
+        }
+    }
+
+    ordered.into_iter().flat_map(|entry| match entry {
+        Ok(metric) => vec![metric],
+        Err(key) => {
+            let (latest, values) = series.remove(&key).unwrap();
+
+            Summary::new(values, percentiles)
+                .map(|summary| summary.series())
+                .unwrap_or_default()
+                .into_iter()
+                .map(|(suffix, value)| latest.derived(&suffix, MetricType::Aggregate(value)))
+                .collect()
+        }
+    }).collect()
+}
+
+/// Options controlling how metrics are aggregated client side for protocols without
+/// server side aggregation, such as Graphite.
+///
+/// Graphite keeps a single value per series and timestamp, so timers and histograms are
+/// summarized per series over each flush, like a StatsD server would.
+#[derive(Clone)]
+pub struct AggregationOptions {
+    pub histogram_percentiles: Vec<f64>,
+    pub timer_percentiles: Vec<f64>
+}
+
+impl Default for AggregationOptions {
+    fn default() -> Self {
+        AggregationOptions {
+            histogram_percentiles: DEFAULT_PERCENTILES.to_vec(),
+            timer_percentiles: DEFAULT_PERCENTILES.to_vec()
+        }
+    }
+}
+
+impl AggregationOptions {
+    /// Aggregate a batch of metrics about to be flushed
+    pub fn aggregate(&self, metrics: Vec<MetricData>) -> Vec<MetricData> {
+        let metrics = summarize(metrics, &self.histogram_percentiles, |metric| match metric {
+            MetricType::Histogram(value) | MetricType::Distribution(value) => Some(*value),
+            _ => None
+        });
+
+        summarize(metrics, &self.timer_percentiles, |metric| match metric {
+            MetricType::Timer(elapsed) => Some(*elapsed as f64),
+            _ => None
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::aggregate::{percentile_suffix, summarize, AggregationOptions, Summary};
+    use crate::metric::{histogram_test_data, timer_test_data, MetricData, MetricType, Series};
+
+    #[test]
+    pub fn it_should_compute_nearest_rank_percentiles() {
+        let values = (1..=100).rev().map(f64::from).collect();
+        let summary = Summary::new(values, &[50.0, 95.0, 99.9]).unwrap();
+
+        assert_eq!(summary.count, 100);
+        assert_eq!(summary.sum, 5050.0);
+        assert_eq!(summary.min, 1.0);
+        assert_eq!(summary.max, 100.0);
+        assert_eq!(summary.mean, 50.5);
+        assert_eq!(summary.percentiles, vec![(50.0, 50.0), (95.0, 95.0), (99.9, 100.0)]);
+    }
+
+    #[test]
+    pub fn it_should_not_summarize_nothing() {
+        assert_eq!(Summary::new(Vec::new(), &[50.0]), None);
+    }
+
+    #[test]
+    pub fn it_should_name_percentiles() {
+        assert_eq!(percentile_suffix(95.0), "p95");
+        assert_eq!(percentile_suffix(99.9), "p99_9");
+    }
+
+    #[test]
+    pub fn it_should_replace_values_with_their_summary() {
+        let data = histogram_test_data().to_vec();
+        let result = summarize(data, &[50.0], |metric| match metric {
+            MetricType::Histogram(value) => Some(*value),
+            _ => None
+        });
+
+        let result: Vec<(String, MetricType, u64)> = result.into_iter()
+            .map(|metric| (metric.path(), metric.metric().clone(), metric.occurred()))
+            .collect();
+
+        assert_eq!(result, vec![
+            ("test.HelloHistogram.count".to_owned(), MetricType::Aggregate(3.0), 5),
+            ("test.HelloHistogram.sum".to_owned(), MetricType::Aggregate(14.0), 5),
+            ("test.HelloHistogram.min".to_owned(), MetricType::Aggregate(2.0), 5),
+            ("test.HelloHistogram.max".to_owned(), MetricType::Aggregate(8.0), 5),
+            ("test.HelloHistogram.mean".to_owned(), MetricType::Aggregate(14.0 / 3.0), 5),
+            ("test.HelloHistogram.p50".to_owned(), MetricType::Aggregate(4.0), 5),
+            ("test.HelloCounter".to_owned(), MetricType::Counter(12), 2),
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_summarize_timers_with_the_configured_percentiles() {
+        let options = AggregationOptions {
+            timer_percentiles: vec![90.0],
+            ..AggregationOptions::default()
+        };
+
+        let result: Vec<(String, MetricType)> = options.aggregate(timer_test_data().to_vec())
+            .into_iter()
+            .map(|metric| (metric.path(), metric.metric().clone()))
+            .collect();
+
+        assert_eq!(result, vec![
+            ("test.HelloTimer.count".to_owned(), MetricType::Aggregate(4.0)),
+            ("test.HelloTimer.sum".to_owned(), MetricType::Aggregate(100.0)),
+            ("test.HelloTimer.min".to_owned(), MetricType::Aggregate(10.0)),
+            ("test.HelloTimer.max".to_owned(), MetricType::Aggregate(40.0)),
+            ("test.HelloTimer.mean".to_owned(), MetricType::Aggregate(25.0)),
+            ("test.HelloTimer.p90".to_owned(), MetricType::Aggregate(40.0)),
+            ("test.OtherTimer.count".to_owned(), MetricType::Aggregate(1.0)),
+            ("test.OtherTimer.sum".to_owned(), MetricType::Aggregate(5.0)),
+            ("test.OtherTimer.min".to_owned(), MetricType::Aggregate(5.0)),
+            ("test.OtherTimer.max".to_owned(), MetricType::Aggregate(5.0)),
+            ("test.OtherTimer.mean".to_owned(), MetricType::Aggregate(5.0)),
+            ("test.OtherTimer.p90".to_owned(), MetricType::Aggregate(5.0)),
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_summarize_each_tagged_series_separately() {
+        let timer = |elapsed, env: &str| {
+            MetricData::new(None, "HelloTimer".to_owned(), 1, MetricType::Timer(elapsed))
+                .with_tags(vec![("env".to_owned(), env.to_owned())])
+        };
+
+        let data = vec![timer(10, "prod"), timer(20, "staging"), timer(30, "prod")];
+        let result: Vec<(Series, MetricType)> = summarize(data, &[], |metric| match metric {
+            MetricType::Timer(elapsed) => Some(*elapsed as f64),
+            _ => None
+        }).into_iter()
+            .filter(|metric| metric.path().ends_with(".count"))
+            .map(|metric| (metric.series(), metric.metric().clone()))
+            .collect();
+
+        let series = |env: &str| ("HelloTimer.count".to_owned(), vec![("env".to_owned(), env.to_owned())]);
+        assert_eq!(result, vec![
+            (series("prod"), MetricType::Aggregate(2.0)),
+            (series("staging"), MetricType::Aggregate(1.0)),
+        ]);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::collector::{BufferedCollector, Collector, FanoutCollector};
+use crate::configuration::Configuration;
+use crate::metric::{self, Namespace};
+use crate::stats::Stats;
+use std::sync::Arc;
+#[cfg(feature = "prometheus")]
+use crate::prometheus::PrometheusCollector;
+#[cfg(feature = "prometheus")]
+use crate::MetricalError;
+#[cfg(feature = "prometheus")]
+use std::net::{SocketAddr, TcpListener};
+
+/// A metrics client which reports to its own destination.
+///
+/// Each client owns a collector built from its `Configuration`, so separate clients can send
+/// metrics to different servers or protocols. Metrics report to the client which created them.
+/// Clients are cheap to clone; clones share the same collector.
+///
+/// The free functions in the crate root (`metrical::counter`, `metrical::flush`, ...) use a
+/// default client configured by `from_env` or `from_config`.
+///
+/// # Example
+/// ```no_run
+/// use metrical::{ConfigurationBuilder, MetricsClient, NetworkProtocol, Protocol};
+/// use std::net::{IpAddr, Ipv4Addr};
+///
+/// let configuration = ConfigurationBuilder::new()
+///     .ip_addr(IpAddr::V4(Ipv4Addr::LOCALHOST))
+///     .port(8125)
+///     .protocol(Protocol::StatsD)
+///     .network_protocol(NetworkProtocol::UDP)
+///     .build()
+///     .expect("Unable to build configuration");
+///
+/// let client = MetricsClient::new(configuration);
+///
+/// let mut counter = client.counter("requests".to_owned());
+/// counter.increment(1);
+/// drop(counter);
+///
+/// client.flush();
+/// ```
+#[derive(Clone)]
+pub struct MetricsClient {
+    collector: Arc<dyn Collector>
+}
+
+impl MetricsClient {
+    /// Create a client which buffers metrics and sends them as described by the configuration.
+    /// When the configuration has sinks, metrics are sent to every one of them as well.
+    pub fn new(mut configuration: Configuration) -> Self {
+        let mut sinks = configuration.take_sinks();
+        if sinks.is_empty() {
+            return MetricsClient::from_collector(Arc::new(BufferedCollector::new(configuration)));
+        }
+
+        let namespace = configuration.options.namespace.clone();
+        sinks.insert(0, configuration);
+        MetricsClient::from_collector(Arc::new(FanoutCollector::new(sinks, namespace)))
+    }
+
+    /// Create a client which keeps the current value of every series, and serves them to
+    /// Prometheus on `/metrics` from an HTTP listener bound to the given address.
+    ///
+    /// The listener stops once the client, its clones and its metrics are dropped.
+    #[cfg(feature = "prometheus")]
+    pub fn prometheus(addr: SocketAddr, namespace: Option<String>) -> Result<Self, MetricalError> {
+        let listener = TcpListener::bind(addr).map_err(MetricalError::Io)?;
+        let collector = PrometheusCollector::new(listener, namespace)?;
+        Ok(MetricsClient::from_collector(Arc::new(collector)))
+    }
+
+    pub(crate) fn from_collector(collector: Arc<dyn Collector>) -> Self {
+        MetricsClient { collector }
+    }
+
+    /// Create a counter reporting to this client
+    pub fn counter(&self, name: String) -> metric::Counter {
+        let mut ctor = metric::CounterBuilder::new(name, self.collector.clone());
+        ctor.namespace(self.collector.namespace()).build()
+    }
+
+    /// Create a gauge reporting to this client
+    pub fn gauge(&self, name: String) -> metric::Gauge {
+        let mut ctor = metric::GaugeBuilder::new(name, self.collector.clone());
+        ctor.namespace(self.collector.namespace()).build()
+    }
+
+    /// Create a timer reporting to this client
+    pub fn timer(&self, name: String) -> metric::Timer {
+        let mut ctor = metric::TimerBuilder::new(name, self.collector.clone());
+        ctor.namespace(self.collector.namespace()).build()
+    }
+
+    /// Create a set reporting to this client
+    pub fn set(&self, name: String) -> metric::Set {
+        let mut ctor = metric::SetBuilder::new(name, self.collector.clone());
+        ctor.namespace(self.collector.namespace()).build()
+    }
+
+    /// Create a histogram reporting to this client
+    pub fn histogram(&self, name: String) -> metric::Histogram {
+        metric::HistogramBuilder::new(name, self.collector.clone())
+            .namespace(self.collector.namespace())
+            .build()
+    }
+
+    /// Create a DogStatsD distribution reporting to this client
+    pub fn distribution(&self, name: String) -> metric::Histogram {
+        metric::HistogramBuilder::new(name, self.collector.clone())
+            .namespace(self.collector.namespace())
+            .kind(metric::HistogramKind::Distribution)
+            .build()
+    }
+
+    /// Extend the namespace of this client. Metrics created from the namespace report to this client.
+    pub fn namespace(&self, namespace: String) -> Namespace {
+        let path = match self.collector.namespace() {
+            Some(path) => format!("{}.{}", path, namespace),
+            None => namespace
+        };
+
+        Namespace::new(path, self.collector.clone())
+    }
+
+    /// Force any metrics buffered by this client to be published
+    pub fn flush(&self) {
+        self.collector.flush();
+    }
+
+    /// The number of metrics this client has discarded because its buffer was full
+    pub fn dropped(&self) -> u64 {
+        self.collector.stats().dropped
+    }
+
+    /// A snapshot of this client's own activity, summed over every sink
+    pub fn stats(&self) -> Stats {
+        self.collector.stats()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::client::MetricsClient;
+    use crate::configuration::local_test_configuration;
+    use crate::protocol::{Compression, NetworkProtocol, Protocol};
+    use std::net::UdpSocket;
+    use std::time::Duration;
+
+    fn client(listener: &UdpSocket, protocol: Protocol, namespace: Option<String>) -> MetricsClient {
+        let port = listener.local_addr().unwrap().port();
+        let configuration = local_test_configuration(port, protocol, NetworkProtocol::UDP)
+            .namespace(namespace)
+            .build()
+            .unwrap();
+
+        MetricsClient::new(configuration)
+    }
+
+    fn receive(listener: &UdpSocket) -> std::io::Result<String> {
+        let mut buf = [0; 256];
+        let received = listener.recv(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf[..received]).into_owned())
+    }
+
+    #[test]
+    pub fn it_should_report_metrics_to_the_client_which_created_them() -> std::io::Result<()> {
+        let statsd = UdpSocket::bind("127.0.0.1:0")?;
+        statsd.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let graphite = UdpSocket::bind("127.0.0.1:0")?;
+        graphite.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let statsd_client = client(&statsd, Protocol::StatsD, Some("first".to_owned()));
+        let graphite_client = client(
+            &graphite, Protocol::Graphite(Compression::Uncompressed), Some("second".to_owned())
+        );
+
+        let mut counter = statsd_client.counter("HelloCounter".to_owned());
+        counter.increment(3);
+        drop(counter);
+
+        let mut gauge = graphite_client.gauge("HelloGauge".to_owned());
+        gauge.increment(4);
+        drop(gauge);
+
+        statsd_client.flush();
+        graphite_client.flush();
+
+        assert_eq!(receive(&statsd)?, "first.HelloCounter:3|c");
+        assert!(receive(&graphite)?.starts_with("second.HelloGauge +4 "));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_extend_the_client_namespace() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let client = client(&listener, Protocol::StatsD, Some("foo".to_owned()));
+        let namespace = client.namespace("bar".to_owned()).namespace("baz".to_owned());
+        assert_eq!(namespace.path(), "foo.bar.baz");
+
+        let mut counter = namespace.counter("HelloCounter".to_owned());
+        counter.increment(1);
+        drop(counter);
+        client.flush();
+
+        assert_eq!(receive(&listener)?, "foo.bar.baz.HelloCounter:1|c");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_tag_metrics_with_namespace_defaults() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let client = client(&listener, Protocol::DogStatsD, None);
+        let namespace = client.namespace("api".to_owned())
+            .tag("env".to_owned(), "prod".to_owned());
+
+        let mut counter = namespace.namespace("v1".to_owned()).counter("Requests".to_owned());
+        counter.tag("route".to_owned(), "users".to_owned());
+        counter.increment(2);
+        drop(counter);
+        client.flush();
+
+        assert_eq!(receive(&listener)?, "api.v1.Requests:2|c|#env:prod,route:users");
+
+        Ok(())
+    }
+}
@@ -1,45 +1,97 @@
-use crate::configuration::{Configuration, FlushConfigurationOptions};
-use crate::metric::{MetricData, Namespace};
-use std::time::{SystemTime, Duration};
+use crate::configuration::{Configuration, FlushConfigurationOptions, MetricFilter, OverflowPolicy};
+use crate::metric::{MetricData, MetricType, Series};
+use crate::stats::{Stats, Telemetry};
+use std::collections::{HashMap, VecDeque};
+use std::mem::Discriminant;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::JoinHandle;
+use std::time::{Instant, SystemTime, Duration};
+
+/// Identifies the series of a record and its metric type
+type SeriesKey = (Series, Discriminant<MetricType>);
 
 struct MetricBuffer{
-    buffer: Option<Vec<MetricData>>,
+    buffer: Option<VecDeque<MetricData>>,
+    /// The position of the record each series is merged into, counted from the first record
+    /// buffered since the last flush
+    series: HashMap<SeriesKey, usize>,
+    /// The number of records dropped from the front of the buffer since the last flush, so
+    /// a position is found at `position - dropped`
+    dropped: usize,
+    /// The approximate memory used by the buffered records
+    bytes: usize,
     flush_settings: FlushConfigurationOptions,
     last_flush: Option<SystemTime>
 }
 
-pub trait Collector {
-    fn send(&mut self, data: MetricData);
-    fn flush(&mut self);
-    fn namespace(&self) -> Option<Namespace>;
+/// Receives metrics as they are recorded.
+///
+/// Collectors are shared between every thread which records metrics, so implementations
+/// must be safe to call concurrently.
+pub trait Collector: Send + Sync {
+    fn send(&self, data: MetricData);
+    fn flush(&self);
+    fn namespace(&self) -> Option<String>;
+
+    /// A snapshot of the collector's own activity
+    fn stats(&self) -> Stats {
+        Stats::default()
+    }
+}
+
+/// Locks the mutex, recovering the guard if another thread panicked while holding it.
+///
+/// Metrics are recorded from `Drop` implementations, so a poisoned lock must never cause
+/// a second panic.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A collector which discards every metric it receives.
+///
+/// This collector is used until metrical has been initialized, so libraries can record
+/// metrics without requiring every binary or test to configure metrical first.
+pub struct NullCollector;
+
+impl Collector for NullCollector {
+    fn send(&self, _data: MetricData) {}
+
+    fn flush(&self) {}
+
+    fn namespace(&self) -> Option<String> {
+        None
+    }
 }
 
 impl MetricBuffer {
-    fn new_buffer(flush_after_amount: Option<usize>) -> Vec<MetricData> {
+    fn new_buffer(flush_after_amount: Option<usize>) -> VecDeque<MetricData> {
         match flush_after_amount {
-            Some(v) => Vec::with_capacity(v),
-            None => Vec::new()
+            Some(v) => VecDeque::with_capacity(v),
+            None => VecDeque::new()
         }
     }
 
     fn new(flush_settings: FlushConfigurationOptions) -> MetricBuffer {
         MetricBuffer{
             buffer: Some(MetricBuffer::new_buffer(flush_settings.flush_after_amount)),
+            series: HashMap::new(),
+            dropped: 0,
+            bytes: 0,
             flush_settings,
-            last_flush: None
+            last_flush: Some(SystemTime::now())
         }
     }
 
     pub fn flush(&mut self) -> Vec<MetricData> {
-        let data = match self.buffer.take() {
-            Some(buffer) => buffer,
-            None => Vec::new()
-        };
+        let data = self.buffer.take().unwrap_or_default();
 
-        self.buffer = Some(Vec::new());
+        self.buffer = Some(VecDeque::new());
+        self.series.clear();
+        self.dropped = 0;
+        self.bytes = 0;
         self.last_flush = Some(SystemTime::now());
 
-        data
+        data.into()
     }
 
     pub fn flush_ready(&self) -> bool {
@@ -62,51 +114,918 @@ impl MetricBuffer {
         })
     }
 
+    /// Whether the buffer is at one of its limits
+    pub fn is_full(&self) -> bool {
+        let settings = &self.flush_settings;
+        let records = self.buffer.as_ref().map_or(0, VecDeque::len);
+
+        settings.max_buffered_records.is_some_and(|max| records >= max)
+            || settings.max_buffered_bytes.is_some_and(|max| self.bytes >= max)
+    }
+
+    /// Removes the oldest buffered record, returning whether there was one
+    pub fn drop_oldest(&mut self) -> bool {
+        let oldest = match self.buffer.as_mut().unwrap().pop_front() {
+            Some(oldest) => oldest,
+            None => return false
+        };
+
+        self.bytes = self.bytes.saturating_sub(oldest.size());
+        if !self.series.is_empty() {
+            let key = (oldest.series(), std::mem::discriminant(oldest.metric()));
+            if self.series.get(&key) == Some(&self.dropped) {
+                self.series.remove(&key);
+            }
+        }
+        self.dropped += 1;
+
+        true
+    }
+
     pub fn submit(&mut self, data: MetricData) {
-        self.buffer.as_mut().unwrap().push(data);
+        if let Err(data) = self.merge(data) {
+            self.push(data);
+        }
+    }
+
+    /// Merges the record into the record already buffered for its series, handing it back
+    /// if there is none or pre-aggregation is off.
+    ///
+    /// Counters and gauges are merged, so the buffer holds one record per series rather
+    /// than one per event.
+    #[allow(clippy::result_large_err)]
+    fn merge(&mut self, data: MetricData) -> Result<(), MetricData> {
+        if !self.flush_settings.pre_aggregate {
+            return Err(data);
+        }
+
+        let key = (data.series(), std::mem::discriminant(data.metric()));
+        match self.series.get(&key) {
+            Some(position) => self.buffer.as_mut().unwrap()[*position - self.dropped].merge(data),
+            None => Err(data)
+        }
+    }
+
+    /// Appends the record as a new record of the buffer
+    fn push(&mut self, data: MetricData) {
+        let buffer = self.buffer.as_mut().unwrap();
+
+        if self.flush_settings.pre_aggregate {
+            if let MetricType::Counter(_) | MetricType::Gauge(_) = data.metric() {
+                let key = (data.series(), std::mem::discriminant(data.metric()));
+                self.series.insert(key, self.dropped + buffer.len());
+            }
+        }
+        self.bytes += data.size();
+        buffer.push_back(data);
     }
 }
 
 impl Drop for BufferedCollector {
     fn drop(&mut self) {
-        let data = self.buffer.flush();
-        self.config.send(data);
+        if let Some(flusher) = self.flusher.take() {
+            flusher.stop();
+        }
+
+        self.state.flush();
+    }
+}
+
+/// The buffer and connection of a `BufferedCollector`, shared with its flusher thread.
+///
+/// The buffer and the connection are guarded separately, so threads recording metrics
+/// are never blocked behind a network write.
+struct BufferedState {
+    config: Mutex<Configuration>,
+    buffer: Mutex<MetricBuffer>,
+    /// Signalled whenever the buffer is drained, waking threads blocked on a full buffer
+    drained: Condvar,
+    telemetry: Arc<Telemetry>,
+    telemetry_namespace: Option<String>
+}
+
+impl BufferedState {
+    fn flush(&self) {
+        // The connection is locked before the buffer is drained, so concurrent flushes
+        // publish batches in the order they were taken from the buffer.
+        let mut config = lock(&self.config);
+        let mut data = lock(&self.buffer).flush();
+        self.drained.notify_all();
+
+        let records = data.len();
+        if let Some(namespace) = &self.telemetry_namespace {
+            data.extend(self.telemetry.snapshot().metrics(namespace));
+        }
+
+        let started = Instant::now();
+        config.send(data);
+        self.telemetry.flushed(records, started.elapsed());
     }
 }
 
+/// Requests made to a `Flusher` thread, guarded by its mutex
+#[derive(Default)]
+struct FlusherSignal {
+    stopped: bool,
+    /// The number of flushes requested, and the number of requests served
+    requested: u64,
+    served: u64
+}
+
+/// A background thread which flushes a collector's buffer every flush interval, and
+/// whenever a flush is requested.
+struct Flusher {
+    signal: Arc<(Mutex<FlusherSignal>, Condvar)>,
+    handle: JoinHandle<()>
+}
+
+impl Flusher {
+    fn start(state: Arc<BufferedState>, interval: Option<Duration>) -> std::io::Result<Flusher> {
+        let signal = Arc::new((Mutex::new(FlusherSignal::default()), Condvar::new()));
+
+        let thread_signal = signal.clone();
+        let handle = std::thread::Builder::new()
+            .name("metrical-flusher".to_owned())
+            .spawn(move || {
+                let (lock_signal, wake) = &*thread_signal;
+                let mut signal = lock(lock_signal);
+
+                while !signal.stopped {
+                    if signal.requested > signal.served {
+                        let requested = signal.requested;
+                        drop(signal);
+                        state.flush();
+
+                        signal = lock(lock_signal);
+                        signal.served = requested;
+                        wake.notify_all();
+                        continue;
+                    }
+
+                    signal = match interval {
+                        Some(interval) => {
+                            let (guard, timeout) = wake
+                                .wait_timeout(signal, interval)
+                                .unwrap_or_else(PoisonError::into_inner);
+
+                            if !guard.stopped && guard.requested == guard.served && timeout.timed_out() {
+                                drop(guard);
+                                state.flush();
+                                lock(lock_signal)
+                            } else {
+                                guard
+                            }
+                        },
+                        None => wake.wait(signal).unwrap_or_else(PoisonError::into_inner)
+                    };
+                }
+            })?;
+
+        Ok(Flusher { signal, handle })
+    }
+
+    /// Asks the thread to flush without waiting for it, returning the request to wait on
+    fn request(&self) -> u64 {
+        let (signal, wake) = &*self.signal;
+        let mut signal = lock(signal);
+        signal.requested += 1;
+        wake.notify_all();
+
+        signal.requested
+    }
+
+    /// Waits until the thread has served the request, or stopped
+    fn wait(&self, request: u64) {
+        let (signal, wake) = &*self.signal;
+        let signal = lock(signal);
+        drop(wake
+            .wait_while(signal, |signal| signal.served < request && !signal.stopped)
+            .unwrap_or_else(PoisonError::into_inner));
+    }
+
+    /// Signals the thread to stop and waits for it to exit.
+    fn stop(self) {
+        let (signal, wake) = &*self.signal;
+        lock(signal).stopped = true;
+        wake.notify_all();
+
+        let _ = self.handle.join();
+    }
+}
+
+/// A collector which buffers metrics in memory until the flush settings of its
+/// configuration are met.
+///
+/// When a flush interval is configured, a background thread flushes the buffer on that
+/// interval even if no metrics arrive. Dropping the collector stops the thread and
+/// flushes whatever remains in the buffer.
 pub struct BufferedCollector {
-    config: Configuration,
-    buffer: MetricBuffer,
+    state: Arc<BufferedState>,
+    flusher: Option<Flusher>,
+    filter: Option<MetricFilter>,
+    namespace: Option<String>
 }
 
 impl BufferedCollector {
     pub fn new(config: Configuration) -> BufferedCollector {
+        BufferedCollector::start(config, false)
+    }
+
+    /// A collector whose flusher thread is started even without a flush interval, so
+    /// flushes can be handed to it with `request_flush`.
+    fn with_flusher(config: Configuration) -> BufferedCollector {
+        BufferedCollector::start(config, true)
+    }
+
+    fn start(config: Configuration, always_flusher: bool) -> BufferedCollector {
         let flush_settings = config.options.flush.clone();
+        let namespace = config.options.namespace.clone();
+        let filter = config.filter();
+        let telemetry = config.telemetry();
+        let telemetry_namespace = flush_settings.telemetry_namespace.clone();
+
+        let interval = flush_settings.flush_after_interval
+            .filter(|interval| *interval > 0)
+            .map(Duration::from_secs);
+
+        let state = Arc::new(BufferedState {
+            config: Mutex::new(config),
+            buffer: Mutex::new(MetricBuffer::new(flush_settings)),
+            drained: Condvar::new(),
+            telemetry,
+            telemetry_namespace
+        });
+
+        // Without a flusher thread the buffer is still flushed when metrics are sent.
+        let flusher = if interval.is_some() || always_flusher {
+            Flusher::start(state.clone(), interval).ok()
+        } else {
+            None
+        };
 
         BufferedCollector {
-            config,
-            buffer: MetricBuffer::new(flush_settings)
+            state,
+            flusher,
+            filter,
+            namespace
+        }
+    }
+
+    /// Hands a flush to the flusher thread, returning the request to wait on. Without a
+    /// flusher thread the buffer is flushed on the calling thread instead.
+    fn request_flush(&self) -> Option<u64> {
+        match &self.flusher {
+            Some(flusher) => Some(flusher.request()),
+            None => {
+                self.flush();
+                None
+            }
+        }
+    }
+
+    /// Waits for a flush handed to the flusher thread to complete
+    fn wait_for_flush(&self, request: Option<u64>) {
+        if let (Some(flusher), Some(request)) = (&self.flusher, request) {
+            flusher.wait(request);
         }
     }
 
+    /// Buffers the metric unless the filter rejects it, returning whether the buffer is
+    /// ready to be flushed.
+    fn submit(&self, metric: MetricData) -> bool {
+        if let Some(filter) = &self.filter {
+            if !filter(&metric.path()) {
+                return false;
+            }
+        }
+
+        let mut buffer = lock(&self.state.buffer);
+
+        // A metric merged into a buffered record takes no room, so it is never dropped.
+        let metric = match buffer.merge(metric) {
+            Ok(()) => {
+                self.state.telemetry.buffered();
+                return buffer.flush_ready();
+            },
+            Err(metric) => metric
+        };
+
+        if buffer.is_full() {
+            match buffer.flush_settings.overflow_policy {
+                OverflowPolicy::DropNewest => {},
+                OverflowPolicy::DropOldest => {
+                    while buffer.is_full() && buffer.drop_oldest() {
+                        self.state.telemetry.dropped();
+                    }
+                },
+                OverflowPolicy::Block(timeout) => {
+                    // The flush settings may never drain a full buffer by themselves, so a
+                    // flush is asked for before waiting.
+                    drop(buffer);
+                    self.request_flush();
+                    buffer = self.state.drained
+                        .wait_timeout_while(lock(&self.state.buffer), timeout, |buffer| buffer.is_full())
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                },
+                OverflowPolicy::Flush => {
+                    drop(buffer);
+                    self.state.flush();
+                    buffer = lock(&self.state.buffer);
+                }
+            }
+
+            // Other threads may have filled the buffer again in the meantime.
+            if buffer.is_full() {
+                self.state.telemetry.dropped();
+                return buffer.flush_ready();
+            }
+        }
+
+        self.state.telemetry.buffered();
+        buffer.submit(metric);
+        buffer.flush_ready()
+    }
 }
 
 impl Collector for BufferedCollector {
-    fn send(&mut self, metric: MetricData) {
-        self.buffer.submit(metric);
-
-        if self.buffer.flush_ready() {
+    fn send(&self, metric: MetricData) {
+        if self.submit(metric) {
             self.flush()
         }
     }
 
-    fn flush(&mut self) {
-        let data = self.buffer.flush();
-        self.config.send(data);
+    fn flush(&self) {
+        self.state.flush();
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.namespace.clone()
     }
 
-    fn namespace(&self) -> Option<Namespace> {
-        self.config.options.namespace.clone()
+    fn stats(&self) -> Stats {
+        self.state.telemetry.snapshot()
     }
 }
 
+/// A collector which sends every metric to several destinations.
+///
+/// Each destination, or sink, is a `BufferedCollector` with its own buffer, connection and
+/// flusher thread. Flushes are handed to the sinks' flusher threads, so a slow or
+/// unreachable sink does not delay the others, nor the thread recording metrics.
+pub struct FanoutCollector {
+    sinks: Vec<BufferedCollector>,
+    namespace: Option<String>
+}
+
+impl FanoutCollector {
+    pub fn new(configurations: Vec<Configuration>, namespace: Option<String>) -> FanoutCollector {
+        FanoutCollector {
+            sinks: configurations.into_iter().map(BufferedCollector::with_flusher).collect(),
+            namespace
+        }
+    }
+}
+
+impl Collector for FanoutCollector {
+    fn send(&self, metric: MetricData) {
+        for sink in &self.sinks {
+            if sink.submit(metric.clone()) {
+                sink.request_flush();
+            }
+        }
+    }
+
+    /// Flushes every sink concurrently, waiting until each has sent its metrics
+    fn flush(&self) {
+        let requests: Vec<Option<u64>> = self.sinks.iter().map(BufferedCollector::request_flush).collect();
+
+        for (sink, request) in self.sinks.iter().zip(requests) {
+            sink.wait_for_flush(request);
+        }
+    }
+
+    fn namespace(&self) -> Option<String> {
+        self.namespace.clone()
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for sink in &self.sinks {
+            stats += sink.stats();
+        }
+        stats
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collector::{lock, BufferedCollector, Collector, FanoutCollector, MetricBuffer};
+    use crate::configuration::{local_test_configuration, ConfigurationBuilder, FlushConfigurationOptions, OverflowPolicy};
+    use crate::metric::{metric_test_data, GaugeOptions, MetricData, MetricType};
+    use crate::protocol::{Compression, NetworkProtocol, Protocol};
+    use std::net::{TcpListener, UdpSocket};
+    use std::time::{Duration, Instant};
+
+    fn statsd(listener: &UdpSocket) -> ConfigurationBuilder {
+        local_test_configuration(listener.local_addr().unwrap().port(), Protocol::StatsD, NetworkProtocol::UDP)
+    }
+
+    fn graphite(listener: &UdpSocket) -> ConfigurationBuilder {
+        let port = listener.local_addr().unwrap().port();
+        local_test_configuration(port, Protocol::Graphite(Compression::Uncompressed), NetworkProtocol::UDP)
+    }
+
+    /// A Graphite configuration sending to a TCP port nothing listens on
+    fn unreachable() -> std::io::Result<ConfigurationBuilder> {
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        Ok(local_test_configuration(port, Protocol::Graphite(Compression::Uncompressed), NetworkProtocol::TCP))
+    }
+
+    fn listen() -> std::io::Result<UdpSocket> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+        Ok(listener)
+    }
+
+    fn collector(listener: &UdpSocket, interval: Option<u64>) -> BufferedCollector {
+        BufferedCollector::new(statsd(listener).flush_after_interval(interval).build().unwrap())
+    }
+
+    fn bounded(listener: &UdpSocket, max_records: usize, policy: OverflowPolicy) -> ConfigurationBuilder {
+        statsd(listener)
+            .max_buffered_records(Some(max_records))
+            .overflow_policy(policy)
+    }
+
+    fn receive(listener: &UdpSocket) -> std::io::Result<String> {
+        let mut buf = [0; 1432];
+        let received = listener.recv(&mut buf)?;
+        Ok(String::from_utf8_lossy(&buf[..received]).into_owned())
+    }
+
+    fn buffer(pre_aggregate: bool) -> MetricBuffer {
+        MetricBuffer::new(FlushConfigurationOptions {
+            pre_aggregate,
+            ..FlushConfigurationOptions::default()
+        })
+    }
+
+    fn gauge(name: &str, occurred: u64, change: GaugeOptions) -> MetricData {
+        MetricData::new(Some("test".to_owned()), name.to_owned(), occurred, MetricType::Gauge(change))
+    }
+
+    fn counter(name: &str, occurred: u64, count: u32) -> MetricData {
+        MetricData::new(Some("test".to_owned()), name.to_owned(), occurred, MetricType::Counter(count))
+    }
+
+    #[test]
+    pub fn it_should_buffer_every_record_without_pre_aggregation() {
+        let mut buffer = buffer(false);
+        buffer.submit(counter("HelloCounter", 1, 1));
+        buffer.submit(counter("HelloCounter", 2, 1));
+
+        assert_eq!(buffer.flush().len(), 2);
+    }
+
+    #[test]
+    pub fn it_should_sum_counters_of_the_same_series() {
+        let mut buffer = buffer(true);
+        for occurred in 1..=1000 {
+            buffer.submit(counter("HelloCounter", occurred, 2));
+        }
+        buffer.submit(counter("OtherCounter", 3, 5));
+
+        assert_eq!(buffer.flush(), vec![
+            counter("HelloCounter", 1000, 2000),
+            counter("OtherCounter", 3, 5),
+        ]);
+        assert_eq!(buffer.flush(), Vec::new());
+    }
+
+    #[test]
+    pub fn it_should_net_gauge_increases_and_decreases() {
+        let mut buffer = buffer(true);
+        buffer.submit(gauge("HelloGauge", 1, GaugeOptions::Increase(5)));
+        buffer.submit(gauge("HelloGauge", 2, GaugeOptions::Decrease(2)));
+        buffer.submit(gauge("OtherGauge", 2, GaugeOptions::Increase(1)));
+        buffer.submit(gauge("OtherGauge", 3, GaugeOptions::Decrease(4)));
+
+        assert_eq!(buffer.flush(), vec![
+            gauge("HelloGauge", 2, GaugeOptions::Increase(3)),
+            gauge("OtherGauge", 3, GaugeOptions::Decrease(3)),
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_apply_gauge_changes_to_a_set_value() {
+        let mut buffer = buffer(true);
+        buffer.submit(gauge("HelloGauge", 1, GaugeOptions::Increase(5)));
+        buffer.submit(gauge("HelloGauge", 2, GaugeOptions::Set(10)));
+        buffer.submit(gauge("HelloGauge", 3, GaugeOptions::Increase(3)));
+        buffer.submit(gauge("HelloGauge", 4, GaugeOptions::Decrease(1)));
+
+        buffer.submit(gauge("OtherGauge", 1, GaugeOptions::Set(2)));
+        buffer.submit(gauge("OtherGauge", 2, GaugeOptions::Decrease(5)));
+
+        assert_eq!(buffer.flush(), vec![
+            gauge("HelloGauge", 4, GaugeOptions::Set(12)),
+            gauge("OtherGauge", 2, GaugeOptions::Set(0)),
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_not_merge_other_metric_types() {
+        let mut buffer = buffer(true);
+        let timer = MetricData::new(None, "HelloTimer".to_owned(), 1, MetricType::Timer(5));
+        buffer.submit(timer.clone());
+        buffer.submit(timer.clone());
+        buffer.submit(MetricData::new(None, "HelloTimer".to_owned(), 1, MetricType::Counter(1)));
+
+        assert_eq!(buffer.flush(), vec![
+            timer.clone(),
+            timer,
+            MetricData::new(None, "HelloTimer".to_owned(), 1, MetricType::Counter(1)),
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_flush_on_the_interval_without_new_metrics() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let collector = collector(&listener, Some(1));
+        let started = Instant::now();
+        collector.send(metric_test_data()[1].clone());
+
+        let mut buf = [0; 256];
+        let received = listener.recv(&mut buf)?;
+
+        assert_eq!(&buf[..received], b"test.HelloCounter:12|c");
+        assert!(started.elapsed() >= Duration::from_millis(900));
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    pub fn it_should_separate_the_records_of_each_flush_over_a_stream() -> std::io::Result<()> {
+        use std::io::Read;
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("metrical-flushes-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path)?;
+
+        let configuration = ConfigurationBuilder::new()
+            .socket_path(path.clone())
+            .protocol(Protocol::StatsD)
+            .network_protocol(NetworkProtocol::UnixStream)
+            .build()
+            .unwrap();
+        let collector = BufferedCollector::new(configuration);
+
+        collector.send(counter("a", 1, 1));
+        collector.flush();
+        collector.send(counter("b", 2, 1));
+        collector.flush();
+        drop(collector);
+
+        let (mut stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut received = String::new();
+        stream.read_to_string(&mut received)?;
+
+        assert_eq!(received.split_terminator('\n').collect::<Vec<_>>(), vec!["test.a:1|c", "test.b:1|c"]);
+        assert!(received.ends_with('\n'));
+
+        std::fs::remove_file(&path)
+    }
+
+    #[test]
+    pub fn it_should_flush_remaining_metrics_when_dropped() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let collector = collector(&listener, Some(3600));
+        collector.send(metric_test_data()[1].clone());
+
+        let started = Instant::now();
+        drop(collector);
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let mut buf = [0; 256];
+        let received = listener.recv(&mut buf)?;
+        assert_eq!(&buf[..received], b"test.HelloCounter:12|c");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_fill_up_to_the_record_and_byte_limits() {
+        let mut records = MetricBuffer::new(FlushConfigurationOptions {
+            max_buffered_records: Some(2),
+            ..FlushConfigurationOptions::default()
+        });
+        records.submit(counter("A", 1, 1));
+        assert!(!records.is_full());
+        records.submit(counter("B", 1, 1));
+        assert!(records.is_full());
+        records.flush();
+        assert!(!records.is_full());
+
+        let size = counter("A", 1, 1).size();
+        let mut bytes = MetricBuffer::new(FlushConfigurationOptions {
+            max_buffered_bytes: Some(size * 3 / 2),
+            ..FlushConfigurationOptions::default()
+        });
+        bytes.submit(counter("A", 1, 1));
+        assert!(!bytes.is_full());
+        bytes.submit(counter("B", 1, 1));
+        assert!(bytes.is_full());
+    }
+
+    #[test]
+    pub fn it_should_keep_merging_series_after_dropping_the_oldest() {
+        let mut buffer = buffer(true);
+        buffer.submit(counter("A", 1, 1));
+        buffer.submit(counter("B", 1, 1));
+        buffer.submit(counter("C", 1, 1));
+
+        assert!(buffer.drop_oldest());
+        buffer.submit(counter("C", 2, 2));
+        buffer.submit(counter("A", 2, 4));
+
+        assert_eq!(buffer.flush(), vec![
+            counter("B", 1, 1),
+            counter("C", 2, 3),
+            counter("A", 2, 4),
+        ]);
+        assert!(!buffer.drop_oldest());
+    }
+
+    #[test]
+    pub fn it_should_drop_the_newest_metrics_when_full() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let collector = BufferedCollector::new(bounded(&listener, 2, OverflowPolicy::DropNewest).build().unwrap());
+        for name in ["A", "B", "C"] {
+            collector.send(counter(name, 1, 1));
+        }
+        assert_eq!(collector.stats().dropped, 1);
+
+        collector.flush();
+        assert_eq!(receive(&listener)?, "test.A:1|c\ntest.B:1|c");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_drop_the_oldest_metrics_when_full() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let collector = BufferedCollector::new(bounded(&listener, 2, OverflowPolicy::DropOldest).build().unwrap());
+        for name in ["A", "B", "C"] {
+            collector.send(counter(name, 1, 1));
+        }
+        assert_eq!(collector.stats().dropped, 1);
+
+        collector.flush();
+        assert_eq!(receive(&listener)?, "test.B:1|c\ntest.C:1|c");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_flush_synchronously_when_full() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let collector = BufferedCollector::new(bounded(&listener, 2, OverflowPolicy::Flush).build().unwrap());
+        for name in ["A", "B", "C"] {
+            collector.send(counter(name, 1, 1));
+        }
+        assert_eq!(collector.stats().dropped, 0);
+        assert_eq!(receive(&listener)?, "test.A:1|c\ntest.B:1|c");
+
+        collector.flush();
+        assert_eq!(receive(&listener)?, "test.C:1|c");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_block_until_a_flush_makes_room() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        // The amount is never reached, so only the flush asked for by Block drains the buffer
+        let configuration = bounded(&listener, 1, OverflowPolicy::Block(Duration::from_secs(5)))
+            .flush_after_amount(Some(10))
+            .build()
+            .unwrap();
+        let collector = BufferedCollector::new(configuration);
+
+        let started = Instant::now();
+        collector.send(counter("A", 1, 1));
+        collector.send(counter("B", 1, 1));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(collector.stats().dropped, 0);
+        assert_eq!(receive(&listener)?, "test.A:1|c");
+
+        collector.flush();
+        assert_eq!(receive(&listener)?, "test.B:1|c");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_drop_metrics_after_blocking_for_the_timeout() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+
+        let configuration = bounded(&listener, 1, OverflowPolicy::Block(Duration::from_millis(100)))
+            .flush_after_interval(Some(60))
+            .build()
+            .unwrap();
+        let collector = BufferedCollector::new(configuration);
+
+        // The requested flush waits on the connection until the guard is dropped
+        collector.send(counter("A", 1, 1));
+        let guard = lock(&collector.state.config);
+
+        let started = Instant::now();
+        collector.send(counter("B", 1, 1));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(collector.stats().dropped, 1);
+        drop(guard);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_not_wait_for_a_busy_sink() -> std::io::Result<()> {
+        let busy = UdpSocket::bind("127.0.0.1:0")?;
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let collector = FanoutCollector::new(vec![
+            bounded(&busy, 10, OverflowPolicy::DropNewest).flush_after_amount(Some(1)).build().unwrap(),
+            bounded(&listener, 10, OverflowPolicy::DropNewest).flush_after_amount(Some(1)).build().unwrap(),
+        ], None);
+
+        // A flush of the busy sink waits on its connection until the guard is dropped
+        let guard = lock(&collector.sinks[0].state.config);
+        collector.send(counter("A", 1, 1));
+        assert_eq!(receive(&listener)?, "test.A:1|c");
+        drop(guard);
+
+        collector.flush();
+        assert_eq!(collector.stats().flushed, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_reject_sinks_which_wait_on_a_full_buffer() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        let sink = || bounded(&listener, 1, OverflowPolicy::DropOldest).build().unwrap();
+
+        for policy in [OverflowPolicy::Block(Duration::from_millis(100)), OverflowPolicy::Flush] {
+            assert!(bounded(&listener, 1, policy).sink(sink()).build().is_err());
+
+            let waiting = bounded(&listener, 1, policy).build().unwrap();
+            assert!(bounded(&listener, 1, OverflowPolicy::DropNewest).sink(waiting).build().is_err());
+        }
+        assert!(bounded(&listener, 1, OverflowPolicy::DropNewest).sink(sink()).build().is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_merge_into_a_full_buffer() -> std::io::Result<()> {
+        let listener = UdpSocket::bind("127.0.0.1:0")?;
+        listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let configuration = bounded(&listener, 1, OverflowPolicy::DropNewest)
+            .pre_aggregate(true)
+            .build()
+            .unwrap();
+        let collector = BufferedCollector::new(configuration);
+        collector.send(counter("A", 1, 1));
+        collector.send(counter("A", 2, 2));
+        collector.send(counter("B", 2, 1));
+        assert_eq!(collector.stats().dropped, 1);
+
+        collector.flush();
+        assert_eq!(receive(&listener)?, "test.A:3|c");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_send_every_metric_to_every_sink() -> std::io::Result<()> {
+        let (statsd_listener, graphite_listener) = (listen()?, listen()?);
+
+        let collector = FanoutCollector::new(vec![
+            statsd(&statsd_listener).build().unwrap(),
+            graphite(&graphite_listener).build().unwrap(),
+        ], None);
+        collector.send(counter("HelloCounter", 1, 3));
+        collector.flush();
+
+        assert_eq!(receive(&statsd_listener)?, "test.HelloCounter:3|c");
+        assert_eq!(receive(&graphite_listener)?, "test.HelloCounter 3 1\n");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_only_send_accepted_metrics_to_a_filtered_sink() -> std::io::Result<()> {
+        let (statsd_listener, graphite_listener) = (listen()?, listen()?);
+
+        let collector = FanoutCollector::new(vec![
+            statsd(&statsd_listener).build().unwrap(),
+            graphite(&graphite_listener).filter(|path| path.starts_with("test.api.")).build().unwrap(),
+        ], None);
+        collector.send(counter("api.Requests", 1, 1));
+        collector.send(counter("db.Queries", 1, 2));
+        collector.flush();
+
+        assert_eq!(receive(&statsd_listener)?, "test.api.Requests:1|c\ntest.db.Queries:2|c");
+        assert_eq!(receive(&graphite_listener)?, "test.api.Requests 1 1\n");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_deliver_to_other_sinks_when_one_is_unreachable() -> std::io::Result<()> {
+        let listener = listen()?;
+
+        let collector = FanoutCollector::new(vec![
+            unreachable()?.flush_after_amount(Some(1)).build().unwrap(),
+            statsd(&listener).flush_after_amount(Some(1)).build().unwrap(),
+        ], None);
+
+        for i in 1..=3 {
+            collector.send(counter("HelloCounter", 1, i));
+            assert_eq!(receive(&listener)?, format!("test.HelloCounter:{}|c", i));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_count_sent_payloads_and_send_errors() -> std::io::Result<()> {
+        let listener = listen()?;
+
+        let collector = FanoutCollector::new(vec![
+            statsd(&listener).build().unwrap(),
+            unreachable()?.build().unwrap(),
+        ], None);
+        collector.send(counter("HelloCounter", 1, 3));
+        collector.send(counter("OtherCounter", 1, 4));
+        collector.flush();
+
+        let payload = "test.HelloCounter:3|c\ntest.OtherCounter:4|c";
+        assert_eq!(receive(&listener)?, payload);
+
+        let stats = collector.stats();
+        assert_eq!(stats.buffered, 4);
+        assert_eq!(stats.flushed, 4);
+        assert_eq!(stats.dropped, 0);
+        assert_eq!(stats.packets_sent, 1);
+        assert_eq!(stats.bytes_sent, payload.len() as u64);
+        assert_eq!(stats.send_errors, 1);
+        assert_eq!(stats.flushes, 2);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_send_its_own_stats_under_the_telemetry_namespace() -> std::io::Result<()> {
+        let listener = listen()?;
+
+        let configuration = statsd(&listener)
+            .telemetry_namespace(Some("metrical".to_owned()))
+            .build()
+            .unwrap();
+        let collector = BufferedCollector::new(configuration);
+
+        collector.send(counter("HelloCounter", 1, 3));
+        collector.flush();
+        let first = receive(&listener)?;
+        assert!(first.starts_with("test.HelloCounter:3|c\nmetrical.buffered:1|g\n"), "{}", first);
+        assert!(first.contains("\nmetrical.packets_sent:0|g\n"));
+
+        collector.flush();
+        let second = receive(&listener)?;
+        assert!(second.starts_with("metrical.buffered:1|g\nmetrical.flushed:1|g\n"), "{}", second);
+        assert!(second.contains("\nmetrical.packets_sent:1|g\n"));
+        assert!(second.contains(&format!("\nmetrical.bytes_sent:{}|g\n", first.len())));
+        assert!(second.contains("\nmetrical.flush_latency_ms:"));
+
+        Ok(())
+    }
+}
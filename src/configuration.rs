@@ -1,21 +1,60 @@
 use crate::connection::Connection;
 use crate::protocol::{Protocol, NetworkProtocol};
-use crate::metric::{MetricData, Namespace};
+use crate::metric::MetricData;
+use crate::aggregate::AggregationOptions;
 use std::net::IpAddr;
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use crate::tls::TlsOptions;
+use crate::sharding::{CarbonNode, ConsistentHashRing};
+use crate::spool::{Spool, SpoolOptions};
+use crate::stats::Telemetry;
 use crate::MetricalError;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Decides from its path whether a metric is sent to a destination
+pub(crate) type MetricFilter = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// What happens to a metric recorded while the buffer is full
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum OverflowPolicy {
+    /// Discard the metric being recorded
+    #[default]
+    DropNewest,
+    /// Discard the oldest buffered metric to make room
+    DropOldest,
+    /// Ask for a flush and wait up to the timeout for it to make room, then discard the metric.
+    /// Not allowed in a configuration with sinks.
+    Block(Duration),
+    /// Flush the buffer on the recording thread, waiting for any flush in progress. Not
+    /// allowed in a configuration with sinks.
+    Flush
+}
 
 /// Options used to determine when to send metrics from memory to a server.
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct FlushConfigurationOptions {
     pub flush_after_amount: Option<usize>,
-    pub flush_after_interval: Option<u64>
-
+    pub flush_after_interval: Option<u64>,
+    pub pre_aggregate: bool,
+    /// The most records held in the buffer
+    pub max_buffered_records: Option<usize>,
+    /// The most bytes of memory, approximately, the buffered records may use
+    pub max_buffered_bytes: Option<usize>,
+    pub overflow_policy: OverflowPolicy,
+    /// The namespace metrical's own stats are sent under with every flush, if any
+    pub telemetry_namespace: Option<String>
 }
 
 /// Nonessential options available to a configuration
 pub struct ConfigurationOptions {
-    pub namespace: Option<Namespace>,
-    pub flush: FlushConfigurationOptions
+    pub namespace: Option<String>,
+    /// The maximum size of each packet or write sent to the server
+    pub max_payload_size: usize,
+    pub flush: FlushConfigurationOptions,
+    pub aggregation: AggregationOptions
 }
 
 /// A configuration of the metrical library.
@@ -23,34 +62,53 @@ pub struct ConfigurationOptions {
 /// This configuration contains information necessary to send messages to Graphite
 /// and information on which protocol to use to communicate with Graphite/Statsd
 pub struct Configuration {
-    connection: Connection,
+    /// The destination, or one endpoint per carbon-cache node when sharding
+    endpoints: Vec<Endpoint>,
+    ring: Option<ConsistentHashRing>,
+    telemetry: Arc<Telemetry>,
+    filter: Option<MetricFilter>,
+    /// Additional destinations every metric is also sent to, each with its own buffer
+    sinks: Vec<Configuration>,
     pub protocol: Protocol,
     pub options: ConfigurationOptions,
 }
 
+/// A connection, and the payloads waiting to be sent over it
+struct Endpoint {
+    connection: Connection,
+    /// Payloads of the last flush which could not be sent, resent before the next flush
+    unsent: Vec<Vec<u8>>,
+    /// Payloads which could not be sent, kept on disk until they are, when configured
+    spool: Option<Spool>
+}
+
 /// Helper to build a configuration piece by piece.
+#[derive(Default)]
 pub struct ConfigurationBuilder {
-    ip_addr: Option<IpAddr>,
+    host: Option<String>,
     port: Option<u16>,
+    #[cfg(unix)]
+    socket_path: Option<PathBuf>,
     network_protocol: Option<NetworkProtocol>,
-    namespace: Option<Namespace>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsOptions>,
+    namespace: Option<String>,
     protocol: Option<Protocol>,
     flush_after_amount: Option<usize>,
-    flush_after_interval: Option<u64>
-}
-
-impl Default for ConfigurationBuilder {
-    fn default() -> Self {
-        ConfigurationBuilder {
-            ip_addr: None,
-            port: None,
-            network_protocol: None,
-            protocol: None,
-            namespace: None,
-            flush_after_amount: None,
-            flush_after_interval: None
-        }
-    }
+    flush_after_interval: Option<u64>,
+    pre_aggregate: bool,
+    max_buffered_records: Option<usize>,
+    max_buffered_bytes: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    telemetry_namespace: Option<String>,
+    max_payload_size: Option<usize>,
+    histogram_percentiles: Option<Vec<f64>>,
+    timer_percentiles: Option<Vec<f64>>,
+    shards: Vec<(String, u16, Option<String>)>,
+    replication_factor: Option<usize>,
+    spool: Option<SpoolOptions>,
+    filter: Option<MetricFilter>,
+    sinks: Vec<Configuration>
 }
 
 impl ConfigurationBuilder {
@@ -59,7 +117,14 @@ impl ConfigurationBuilder {
     }
 
     pub fn ip_addr(mut self, ip_addr: IpAddr) -> Self {
-        self.ip_addr = Some(ip_addr);
+        self.host = Some(ip_addr.to_string());
+        self
+    }
+
+    /// The hostname or IP address of the server, such as `statsd.internal`. Hostnames are
+    /// resolved when connecting, and again on reconnects, so DNS changes are picked up.
+    pub fn host(mut self, host: String) -> Self {
+        self.host = Some(host);
         self
     }
 
@@ -68,6 +133,14 @@ impl ConfigurationBuilder {
         self
     }
 
+    /// The path of the Unix domain socket to send to, when using the `UnixDatagram` or
+    /// `UnixStream` network protocols. The host and port are not used for Unix domain sockets.
+    #[cfg(unix)]
+    pub fn socket_path(mut self, path: PathBuf) -> Self {
+        self.socket_path = Some(path);
+        self
+    }
+
     pub fn flush_after_amount(mut self, amount: Option<usize>) -> Self {
         self.flush_after_amount = amount;
         self
@@ -78,6 +151,63 @@ impl ConfigurationBuilder {
         self
     }
 
+    /// Merge counters and gauge changes of the same series while they are buffered, so each
+    /// flush sends a single record per series. Disabled by default.
+    pub fn pre_aggregate(mut self, pre_aggregate: bool) -> Self {
+        self.pre_aggregate = pre_aggregate;
+        self
+    }
+
+    /// The most records held in the buffer between flushes. Unlimited by default, so a flush
+    /// stuck on an unresponsive server lets the buffer grow without bound.
+    pub fn max_buffered_records(mut self, records: Option<usize>) -> Self {
+        self.max_buffered_records = records;
+        self
+    }
+
+    /// The most memory in bytes, approximately, buffered records may use. Unlimited by default.
+    pub fn max_buffered_bytes(mut self, bytes: Option<usize>) -> Self {
+        self.max_buffered_bytes = bytes;
+        self
+    }
+
+    /// What to do with metrics recorded while the buffer is at one of its limits. Defaults to
+    /// dropping them. Dropped metrics are counted by `MetricsClient::dropped`.
+    pub fn overflow_policy(mut self, policy: OverflowPolicy) -> Self {
+        self.overflow_policy = policy;
+        self
+    }
+
+    /// Send metrical's own stats (see `metrical::stats`) under the namespace, such as
+    /// `metrical`, with every flush. They are not sent by default.
+    pub fn telemetry_namespace(mut self, namespace: Option<String>) -> Self {
+        self.telemetry_namespace = namespace;
+        self
+    }
+
+    /// The maximum size in bytes of each packet or write sent to the server. Records are
+    /// never split across packets, so a single record larger than this is sent alone.
+    ///
+    /// Defaults to 1432 bytes over UDP, which fits an Ethernet MTU, and 64 KiB over TCP.
+    pub fn max_payload_size(mut self, size: Option<usize>) -> Self {
+        self.max_payload_size = size;
+        self
+    }
+
+    /// The percentiles (between 0 and 100) to summarize histograms with when publishing to
+    /// Graphite. Defaults to the 50th, 95th and 99th percentiles.
+    pub fn histogram_percentiles(mut self, percentiles: Vec<f64>) -> Self {
+        self.histogram_percentiles = Some(percentiles);
+        self
+    }
+
+    /// The percentiles (between 0 and 100) to summarize timers with when publishing to
+    /// Graphite. Defaults to the 50th, 95th and 99th percentiles.
+    pub fn timer_percentiles(mut self, percentiles: Vec<f64>) -> Self {
+        self.timer_percentiles = Some(percentiles);
+        self
+    }
+
     pub fn protocol(mut self, protocol: Protocol) -> Self {
         self.protocol = Some(protocol);
         self
@@ -88,40 +218,238 @@ impl ConfigurationBuilder {
         self
     }
 
-    pub fn namespace(mut self, namespace: Option<Namespace>) -> Self {
+    /// The TLS options used with `NetworkProtocol::TLS`. Defaults to verifying the server
+    /// against the Mozilla root certificates, without a client certificate.
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, options: TlsOptions) -> Self {
+        self.tls = Some(options);
+        self
+    }
+
+    pub fn namespace(mut self, namespace: Option<String>) -> Self {
         self.namespace = namespace;
         self
     }
 
+    /// Shard series across carbon-cache instances instead of sending every metric to one
+    /// host. Series are placed on the instances with carbon-relay's consistent hash ring, so
+    /// the instance name must match the one carbon-relay and graphite-web are configured
+    /// with, if any (`a` in `DESTINATIONS = 127.0.0.1:2104:a`).
+    ///
+    /// Sharding requires the Graphite protocol over TCP or UDP, and cannot be combined with
+    /// a host and port.
+    pub fn shard(mut self, host: String, port: u16, instance: Option<String>) -> Self {
+        self.shards.push((host, port, instance));
+        self
+    }
+
+    /// The number of shards every series is sent to, as carbon-relay's `REPLICATION_FACTOR`.
+    /// Defaults to 1.
+    pub fn replication_factor(mut self, factor: usize) -> Self {
+        self.replication_factor = Some(factor);
+        self
+    }
+
+    /// Spool payloads which could not be sent to disk, and replay them in order once the
+    /// server can be reached again, instead of only resending the latest failed flush.
+    /// The spool is kept across restarts. When sharding, each shard spools to its own
+    /// subdirectory.
+    pub fn spool(mut self, options: SpoolOptions) -> Self {
+        self.spool = Some(options);
+        self
+    }
+
+    /// Only send the metrics whose path (including the namespace) the filter accepts to
+    /// this destination. Every metric is sent by default.
+    pub fn filter<F>(mut self, filter: F) -> Self
+        where F: Fn(&str) -> bool + Send + Sync + 'static
+    {
+        self.filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Also send every metric to the destination of another configuration, such as a
+    /// Graphite server alongside StatsD. Each sink keeps its own protocol, transport, filter,
+    /// flush settings and buffer, so a slow or unreachable sink does not hold back the others.
+    ///
+    /// The namespace of a sink is not used; metrics are named by this configuration.
+    pub fn sink(mut self, mut sink: Configuration) -> Self {
+        let nested = std::mem::take(&mut sink.sinks);
+        self.sinks.push(sink);
+        self.sinks.extend(nested);
+        self
+    }
+
     pub fn build(self) -> Result<Configuration, MetricalError>  {
-        let protocol = if self.protocol.is_none() {
-            return Err(MetricalError::ConfigurationInvalid("Protocol Unspecified"));
+        let protocol = match self.protocol {
+            Some(protocol) => protocol,
+            None => return Err(MetricalError::ConfigurationInvalid("Protocol Unspecified"))
+        };
+
+        let network_protocol = match self.network_protocol {
+            Some(network_protocol) => network_protocol,
+            None => return Err(MetricalError::ConfigurationInvalid("Network Protocol unspecified"))
+        };
+
+        let ring = if self.shards.is_empty() {
+            None
         } else {
-            self.protocol.unwrap()
+            if !matches!(protocol, Protocol::Graphite(_)) {
+                return Err(MetricalError::ConfigurationInvalid("Sharding requires the Graphite protocol"))
+            }
+            if self.host.is_some() {
+                return Err(MetricalError::ConfigurationInvalid("A host cannot be combined with shards"))
+            }
+
+            let nodes: Vec<CarbonNode> = self.shards.iter()
+                .map(|(host, _, instance)| (host.clone(), instance.clone()))
+                .collect();
+            if nodes.iter().enumerate().any(|(index, node)| nodes[..index].contains(node)) {
+                return Err(MetricalError::ConfigurationInvalid("Shards must be unique"))
+            }
+
+            match self.replication_factor {
+                Some(0) => return Err(MetricalError::ConfigurationInvalid("Replication factor must be positive")),
+                factor => Some(ConsistentHashRing::new(&nodes, factor.unwrap_or(1)))
+            }
         };
 
-        let network_protocol = if self.network_protocol.is_none() {
-            return Err(MetricalError::ConfigurationInvalid("Network Protocol unspecified"))
-        } else { self.network_protocol.unwrap() };
+        let connections = match network_protocol {
+            #[cfg(unix)]
+            NetworkProtocol::UnixDatagram | NetworkProtocol::UnixStream => match self.socket_path {
+                _ if ring.is_some() => return Err(
+                    MetricalError::ConfigurationInvalid("Sharding requires the TCP or UDP network protocol")
+                ),
+                Some(path) => vec![Connection::unix(path, network_protocol)],
+                None => return Err(MetricalError::ConfigurationInvalid("Socket path unspecified"))
+            },
+            _ => {
+                let destinations = if ring.is_some() {
+                    self.shards.into_iter().map(|(host, port, _)| (host, port)).collect()
+                } else {
+                    let host = match self.host {
+                        Some(host) if !host.is_empty() => host,
+                        Some(_) => return Err(MetricalError::ConfigurationInvalid("Host must not be empty")),
+                        None => return Err(MetricalError::ConfigurationInvalid("Host unspecified"))
+                    };
+
+                    let port = match self.port {
+                        Some(port) => port,
+                        None => return Err(MetricalError::ConfigurationInvalid("Port unspecified"))
+                    };
+
+                    vec![(host, port)]
+                };
+
+                #[cfg(feature = "tls")]
+                let tls = self.tls.unwrap_or_default();
 
-        let ip_addr = if self.ip_addr.is_some() { self.ip_addr.unwrap() } else {
-            return Err(MetricalError::ConfigurationInvalid("IP Address unspecified"))
+                let mut connections = Vec::with_capacity(destinations.len());
+                for (host, port) in destinations {
+                    connections.push(match network_protocol {
+                        #[cfg(feature = "tls")]
+                        NetworkProtocol::TLS => {
+                            let connector = tls.connector(&host)?;
+                            Connection::tls(host, port, connector)
+                        },
+                        _ => Connection::new(host, port, network_protocol)
+                    });
+                }
+                connections
+            }
         };
+        let connection = &connections[0];
+        let sharded = ring.is_some();
+
+        if let Protocol::OpenTSDB(_) = protocol {
+            if connection.is_datagram() {
+                return Err(MetricalError::ConfigurationInvalid("OpenTSDB requires a stream network protocol, such as TCP"))
+            }
+        }
 
-        let port = if self.port.is_some() { self.port.unwrap() } else {
-            return Err(MetricalError::ConfigurationInvalid("Port unspecified"))
+        let max_payload_size = match self.max_payload_size {
+            Some(0) => return Err(MetricalError::ConfigurationInvalid("Maximum payload size must be positive")),
+            Some(size) => size,
+            None => connection.default_payload_size()
         };
 
+        if self.max_buffered_records == Some(0) || self.max_buffered_bytes == Some(0) {
+            return Err(MetricalError::ConfigurationInvalid("Buffer limits must be positive"))
+        }
+
+        // Sinks are filled on the thread recording metrics, so a sink which waits on its own
+        // flush would hold back that thread and every other sink.
+        let waits = |policy: OverflowPolicy| matches!(policy, OverflowPolicy::Block(_) | OverflowPolicy::Flush);
+        let sink_waits = self.sinks.iter().any(|sink| waits(sink.options.flush.overflow_policy));
+        if !self.sinks.is_empty() && (waits(self.overflow_policy) || sink_waits) {
+            return Err(MetricalError::ConfigurationInvalid("Sinks cannot block or flush when their buffer is full"))
+        }
+
+        let mut aggregation = AggregationOptions::default();
+        if let Some(percentiles) = self.histogram_percentiles {
+            aggregation.histogram_percentiles = valid_percentiles(percentiles)?;
+        }
+        if let Some(percentiles) = self.timer_percentiles {
+            aggregation.timer_percentiles = valid_percentiles(percentiles)?;
+        }
+
         let configuration_options = ConfigurationOptions{
             namespace: self.namespace,
+            max_payload_size,
             flush: FlushConfigurationOptions {
                 flush_after_interval: self.flush_after_interval,
-                flush_after_amount: self.flush_after_amount
-            }
+                flush_after_amount: self.flush_after_amount,
+                pre_aggregate: self.pre_aggregate,
+                max_buffered_records: self.max_buffered_records,
+                max_buffered_bytes: self.max_buffered_bytes,
+                overflow_policy: self.overflow_policy,
+                telemetry_namespace: self.telemetry_namespace
+            },
+            aggregation
+        };
+
+        let mut endpoints = Vec::with_capacity(connections.len());
+        for (index, connection) in connections.into_iter().enumerate() {
+            let spool = match &self.spool {
+                Some(options) => {
+                    let subdirectory = if sharded { Some(format!("shard-{}", index)) } else { None };
+                    Some(options.open(subdirectory.as_deref()).map_err(MetricalError::Io)?)
+                },
+                None => None
+            };
+
+            endpoints.push(Endpoint { connection, unsent: Vec::new(), spool });
+        }
+
+        let configuration = Configuration {
+            endpoints,
+            ring,
+            telemetry: Arc::default(),
+            filter: self.filter,
+            sinks: self.sinks,
+            protocol,
+            options: configuration_options
         };
 
-        let connection = Connection::new(ip_addr, port, network_protocol);
-        Ok(Configuration::new(connection, protocol, configuration_options))
+        Ok(configuration)
+    }
+}
+
+/// A configuration sending to a port on the loopback interface
+#[cfg(test)]
+pub fn local_test_configuration(port: u16, protocol: Protocol, network_protocol: NetworkProtocol) -> ConfigurationBuilder {
+    ConfigurationBuilder::new()
+        .ip_addr(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST))
+        .port(port)
+        .protocol(protocol)
+        .network_protocol(network_protocol)
+}
+
+fn valid_percentiles(percentiles: Vec<f64>) -> Result<Vec<f64>, MetricalError> {
+    if percentiles.iter().all(|percentile| *percentile > 0.0 && *percentile <= 100.0) {
+        Ok(percentiles)
+    } else {
+        Err(MetricalError::ConfigurationInvalid("Percentiles must be between 0 and 100"))
     }
 }
 
@@ -130,7 +458,11 @@ impl Configuration {
         -> Configuration
     {
         Configuration{
-            connection,
+            endpoints: vec![Endpoint { connection, unsent: Vec::new(), spool: None }],
+            ring: None,
+            telemetry: Arc::default(),
+            filter: None,
+            sinks: Vec::new(),
             protocol,
             options,
         }
@@ -138,15 +470,92 @@ impl Configuration {
 }
 
 impl Configuration {
+    /// The filter deciding which metrics are sent to this destination, if any
+    pub(crate) fn filter(&self) -> Option<MetricFilter> {
+        self.filter.clone()
+    }
+
+    /// The counters of this destination's activity
+    pub(crate) fn telemetry(&self) -> Arc<Telemetry> {
+        self.telemetry.clone()
+    }
+
+    /// Removes the additional destinations from this configuration
+    pub(crate) fn take_sinks(&mut self) -> Vec<Configuration> {
+        std::mem::take(&mut self.sinks)
+    }
+
     pub fn send(&mut self,  data: Vec<MetricData>) {
-        let data = self.protocol.serialize(data);
+        let data = match self.protocol {
+            Protocol::Graphite(_) | Protocol::OpenTSDB(_) => self.options.aggregation.aggregate(data),
+            Protocol::StatsD | Protocol::DogStatsD | Protocol::InfluxLine => data
+        };
 
-        for packet_body in data {
-            match self.connection.send(packet_body.as_ref()) {
-                Ok(_) => {},
-                Err(_) => {}
-            };
+        let batches = match &self.ring {
+            Some(ring) => ring.distribute(data),
+            None => vec![data]
+        };
+
+        for (index, batch) in batches.into_iter().enumerate() {
+            let network_protocol = self.endpoints[index].connection.network_protocol();
+            let packets = self.protocol.serialize(batch, self.options.max_payload_size)
+                .into_iter()
+                .map(|packet| self.protocol.frame(packet, network_protocol))
+                .collect();
+            self.endpoints[index].send(packets, &self.telemetry);
         }
     }
 }
 
+impl Endpoint {
+    fn send(&mut self, batch: Vec<Vec<u8>>, telemetry: &Telemetry) {
+        let reconnects = self.connection.reconnects();
+        self.send_batch(batch, telemetry);
+        telemetry.reconnected(self.connection.reconnects() - reconnects);
+    }
+
+    fn send_batch(&mut self, batch: Vec<Vec<u8>>, telemetry: &Telemetry) {
+        let connection = &mut self.connection;
+        let mut send = |payload: &[u8]| {
+            let sent = connection.send(payload);
+            telemetry.sent(payload.len(), &sent);
+            sent
+        };
+
+        if let Some(spool) = &mut self.spool {
+            // Spooled payloads are older than the batch, so they are replayed first. Until
+            // they are all sent, new payloads are spooled behind them to keep their order.
+            let unsent: Vec<Vec<u8>> = if spool.replay(&mut send) {
+                batch.into_iter().skip_while(|packet| send(packet).is_ok()).collect()
+            } else {
+                batch
+            };
+
+            // The spool is best effort as well; payloads it cannot hold are dropped.
+            let payloads = unsent.len();
+            if spool.append(unsent).is_err() {
+                telemetry.spool_failed(payloads);
+            }
+            return;
+        }
+
+        let batch_len = batch.len();
+
+        let mut packets = std::mem::take(&mut self.unsent);
+        packets.extend(batch);
+
+        // Metrics are best effort. When the server cannot be reached, the unsent payloads of
+        // the latest flush with any metrics are kept and resent with the next flush; older
+        // ones are dropped.
+        let mut packets = packets.into_iter();
+        while let Some(packet_body) = packets.next() {
+            if send(packet_body.as_ref()).is_err() {
+                let failed = packets.len() + 1;
+                let dropped = if batch_len == 0 { 0 } else { failed.saturating_sub(batch_len) };
+                telemetry.payloads_dropped(dropped);
+                self.unsent = std::iter::once(packet_body).chain(packets).skip(dropped).collect();
+                break;
+            }
+        }
+    }
+}
@@ -1,57 +1,425 @@
-use std::net::{IpAddr, UdpSocket};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, ToSocketAddrs, UdpSocket};
 use std::net::{TcpStream, SocketAddr};
+#[cfg(unix)]
+use std::os::unix::net::{UnixDatagram, UnixStream};
+#[cfg(unix)]
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use crate::tls::{TlsConnector, TlsStream};
+use std::time::{Duration, Instant};
 use crate::protocol::NetworkProtocol;
 
+/// How long to wait for the server to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a write may block before the connection is considered broken
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The delay before reconnecting after the first failed attempt, doubled after each failure
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How often the host of a UDP socket is resolved again
+const RESOLVE_INTERVAL: Duration = Duration::from_secs(60);
 
+/// Tracks failed connection attempts, spacing out reconnects with an exponential backoff.
+///
+/// Each delay is jittered between half and all of the backoff, so many processes losing the
+/// same server do not reconnect in lockstep.
+struct Backoff {
+    failures: u32,
+    retry_at: Option<Instant>
+}
+
+impl Backoff {
+    fn new() -> Self {
+        Backoff { failures: 0, retry_at: None }
+    }
+
+    /// Whether a connection may be attempted now
+    fn ready(&self) -> bool {
+        self.retry_at.is_none_or(|retry_at| Instant::now() >= retry_at)
+    }
+
+    /// The backoff after the current number of failures, before jitter
+    fn backoff(&self) -> Duration {
+        INITIAL_BACKOFF
+            .checked_mul(1 << self.failures.min(16))
+            .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF))
+    }
+
+    fn failed(&mut self) {
+        let backoff = self.backoff();
+
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(self.failures);
+        let jitter = backoff / 2 * (hasher.finish() % 1001) as u32 / 1000;
+
+        self.retry_at = Some(Instant::now() + backoff / 2 + jitter);
+        self.failures = self.failures.saturating_add(1);
+    }
+
+    fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_at = None;
+    }
+}
+
+/// Where a connection sends metrics
+enum Destination {
+    /// A hostname or IP address, and port
+    Host(String, u16),
+    /// The path of a Unix domain socket
+    #[cfg(unix)]
+    Path(PathBuf)
+}
+
+/// A stream to the server, over TCP, TLS or a Unix domain socket
+enum Stream {
+    Tcp(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<TlsStream>),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl Stream {
+    fn set_nonblocking(&self, nonblocking: bool) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_nonblocking(nonblocking),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.sock.set_nonblocking(nonblocking),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_nonblocking(nonblocking)
+        }
+    }
+
+    /// Whether the server still has the stream open. Servers never write back to metrical,
+    /// so a readable end of stream or an error means the connection is gone.
+    fn is_open(&mut self) -> bool {
+        if self.set_nonblocking(true).is_err() {
+            return false;
+        }
+
+        let mut buf = [0; 1];
+        let read = match self {
+            Stream::Tcp(stream) => stream.peek(&mut buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Stream::read_tls(stream),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(&mut buf)
+        };
+
+        let open = match read {
+            Ok(0) => false,
+            Ok(_) => true,
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => true,
+            Err(_) => false
+        };
+
+        self.set_nonblocking(false).is_ok() && open
+    }
+
+    /// Processes any records the server sent, such as session tickets or a closure alert,
+    /// without blocking. Returns `Ok(0)` once the server has closed the session.
+    #[cfg(feature = "tls")]
+    fn read_tls(stream: &mut TlsStream) -> std::io::Result<usize> {
+        loop {
+            if stream.conn.read_tls(&mut stream.sock)? == 0 {
+                return Ok(0);
+            }
+
+            let state = stream.conn.process_new_packets().map_err(std::io::Error::other)?;
+            if state.peer_has_closed() {
+                return Ok(0);
+            }
+        }
+    }
+
+    fn write_all(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.write_all(buffer),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.write_all(buffer).and_then(|_| stream.flush()),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write_all(buffer)
+        }
+    }
+}
+
+/// A connection to the server metrics are sent to.
+///
+/// The server may be named by a hostname, which is resolved when connecting. TCP connections
+/// resolve the name again on every reconnect, and UDP sockets every minute, so DNS failovers
+/// are picked up.
+///
+/// UDP datagrams are sent from a single ephemeral socket, bound on the first send.
+/// TCP, TLS and Unix domain socket connections are opened lazily on the first send. When the server
+/// closes or resets the connection, the next send reconnects and resends the failed payload.
+/// The whole payload is resent, so records the server received before the connection broke
+/// may be received twice.
+/// Failed connection attempts are retried with an exponential backoff; sends made while
+/// waiting fail immediately instead of blocking.
 pub struct Connection {
-    addr: SocketAddr,
+    destination: Destination,
+    /// The address the UDP socket is connected to, and when it was resolved
+    resolved: Option<(SocketAddr, Instant)>,
     protocol: NetworkProtocol,
-    stream: Option<TcpStream>
+    stream: Option<Stream>,
+    socket: Option<UdpSocket>,
+    #[cfg(unix)]
+    unix_socket: Option<UnixDatagram>,
+    #[cfg(feature = "tls")]
+    tls: Option<TlsConnector>,
+    backoff: Backoff,
+    /// The number of connections opened
+    opened: u64
 }
 
 impl Connection {
-    pub fn new(addr: IpAddr, port: u16, protocol: NetworkProtocol) -> Self {
-        let addr = SocketAddr::from((addr, port));
-
-        let stream = match protocol {
-            NetworkProtocol::TCP => Option::Some(
-                TcpStream::connect(addr).expect("Unable to connect to TCP Address")
-            ),
-            NetworkProtocol::UDP => Option::None
-        };
+    /// A connection to the host, which is either a hostname or an IP address
+    pub fn new(host: String, port: u16, protocol: NetworkProtocol) -> Self {
+        Connection::to(Destination::Host(host, port), protocol)
+    }
+
+    /// A TLS encrypted TCP connection to the host
+    #[cfg(feature = "tls")]
+    pub fn tls(host: String, port: u16, connector: TlsConnector) -> Self {
+        let mut connection = Connection::to(Destination::Host(host, port), NetworkProtocol::TLS);
+        connection.tls = Some(connector);
+        connection
+    }
+
+    /// A connection to the Unix domain socket at the path
+    #[cfg(unix)]
+    pub fn unix(path: PathBuf, protocol: NetworkProtocol) -> Self {
+        Connection::to(Destination::Path(path), protocol)
+    }
 
+    fn to(destination: Destination, protocol: NetworkProtocol) -> Self {
         Connection{
-            addr,
+            destination,
+            resolved: None,
             protocol,
-            stream
+            stream: None,
+            socket: None,
+            #[cfg(unix)]
+            unix_socket: None,
+            #[cfg(feature = "tls")]
+            tls: None,
+            backoff: Backoff::new(),
+            opened: 0
         }
     }
 
-    pub fn send(&mut self, buffer: &[u8]) -> Result<(), std::io::Error> {
-        use std::io::prelude::*;
-        match self.protocol {
-            NetworkProtocol::TCP => {
-                let mut stream = self.stream.take().unwrap();
-                stream.write(buffer)?;
+    /// Whether payloads are sent as datagrams rather than over a stream
+    pub fn is_datagram(&self) -> bool {
+        self.protocol.is_datagram()
+    }
 
-                self.stream = Option::Some(stream);
+    /// The network protocol payloads are sent over
+    pub fn network_protocol(&self) -> NetworkProtocol {
+        self.protocol
+    }
+
+    /// The maximum payload size used when none is configured
+    pub fn default_payload_size(&self) -> usize {
+        self.protocol.default_payload_size()
+    }
+
+    /// Looks up the addresses of the host
+    fn resolve(&self) -> std::io::Result<Vec<SocketAddr>> {
+        let (host, port) = match &self.destination {
+            Destination::Host(host, port) => (host.trim_start_matches('[').trim_end_matches(']'), *port),
+            #[cfg(unix)]
+            Destination::Path(_) => return Err(std::io::Error::new(
+                ErrorKind::InvalidInput, "Unix domain sockets have no network address"
+            ))
+        };
+
+        let addrs: Vec<SocketAddr> = (host, port).to_socket_addrs()?.collect();
+        if addrs.is_empty() {
+            return Err(std::io::Error::new(ErrorKind::NotFound, "The host has no addresses"));
+        }
+        Ok(addrs)
+    }
 
-                Ok(())
+    /// Connects to the first address of the host which accepts the connection
+    fn connect_tcp(&self) -> std::io::Result<TcpStream> {
+        let mut error = None;
+        for addr in self.resolve()? {
+            match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                    return Ok(stream);
+                },
+                Err(e) => error = Some(e)
+            }
+        }
+
+        Err(error.unwrap_or_else(|| std::io::Error::new(ErrorKind::NotFound, "The host has no addresses")))
+    }
+
+    fn open_stream(&self) -> std::io::Result<Stream> {
+        match &self.destination {
+            #[cfg(feature = "tls")]
+            Destination::Host(_, _) if self.tls.is_some() => {
+                let stream = self.connect_tcp()?;
+                stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+
+                let connector = self.tls.as_ref().ok_or(ErrorKind::NotConnected)?;
+                connector.connect(stream).map(|stream| Stream::Tls(Box::new(stream)))
             },
-            NetworkProtocol::UDP => {
-                let addrs = [
-                    SocketAddr::from(([127, 0, 0, 1], self.addr.port())),
-                    SocketAddr::from(([127, 0, 0, 1], self.addr.port() + 1)),
-                    SocketAddr::from(([127, 0, 0, 1], self.addr.port() + 2)),
-                ];
-
-                let socket = UdpSocket::bind(&addrs[..]).expect("Unable to create UDP Socket");
-                socket.connect(self.addr).expect("Unable to connect to UDP Socket on server");
-                socket.send(buffer)?;
-
-                Ok(())
+            Destination::Host(_, _) => self.connect_tcp().map(Stream::Tcp),
+            #[cfg(unix)]
+            Destination::Path(path) => {
+                let stream = UnixStream::connect(path)?;
+                stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+                Ok(Stream::Unix(stream))
+            }
+        }
+    }
+
+    /// Opens a connection with `open`, unless waiting to reconnect after a failed attempt
+    fn connect<T, F>(&mut self, open: F) -> std::io::Result<T> where F: FnOnce(&Self) -> std::io::Result<T> {
+        if !self.backoff.ready() {
+            return Err(std::io::Error::new(ErrorKind::NotConnected, "Waiting to reconnect"));
+        }
+
+        let connection = open(self);
+
+        match connection {
+            Ok(_) => self.opened += 1,
+            Err(_) => self.backoff.failed()
+        }
+        connection
+    }
+
+    /// The number of times a connection was reopened after the first
+    pub fn reconnects(&self) -> u64 {
+        self.opened.saturating_sub(1)
+    }
+
+    fn write_stream(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        let open = self.stream.as_mut().is_some_and(Stream::is_open);
+        let mut stream = match self.stream.take() {
+            Some(stream) if open => stream,
+            _ => self.connect(Connection::open_stream)?
+        };
+
+        if stream.write_all(buffer).is_err() {
+            // The connection broke since the last send; resend the payload over a new one.
+            // Part of it may have reached the server already, and is then received twice:
+            // which bytes arrived cannot be known, and binary framings cannot be split.
+            stream = self.connect(Connection::open_stream)?;
+            if let Err(e) = stream.write_all(buffer) {
+                self.backoff.failed();
+                return Err(e);
             }
         }
+
+        self.backoff.succeeded();
+        self.stream = Some(stream);
+        Ok(())
+    }
+
+    /// Binds an ephemeral socket of the same address family as the server, connected to it
+    fn bind_udp(addr: SocketAddr) -> std::io::Result<UdpSocket> {
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(socket)
+    }
+
+    /// The UDP socket to send from, bound on the first send. The host is resolved again once
+    /// `RESOLVE_INTERVAL` has passed, and the socket replaced if its address changed. If the
+    /// host cannot be resolved, the previous address keeps being used.
+    fn udp_socket(&mut self) -> std::io::Result<UdpSocket> {
+        let expired = self.resolved.is_none_or(|(_, at)| at.elapsed() >= RESOLVE_INTERVAL);
+
+        match (self.socket.take(), expired) {
+            (Some(socket), false) => Ok(socket),
+            (socket, _) => {
+                let addr = match (self.resolve(), &self.resolved) {
+                    (Ok(addrs), _) => addrs[0],
+                    (Err(_), Some((addr, _))) => *addr,
+                    (Err(e), None) => return Err(e)
+                };
+
+                let socket = match socket {
+                    Some(socket) if self.resolved.is_some_and(|(previous, _)| previous == addr) => socket,
+                    _ => Connection::bind_udp(addr)?
+                };
+
+                self.resolved = Some((addr, Instant::now()));
+                Ok(socket)
+            }
+        }
+    }
+
+    /// Checks a datagram was sent whole
+    fn sent_whole(sent: std::io::Result<usize>, buffer: &[u8]) -> std::io::Result<()> {
+        match sent {
+            Ok(sent) if sent < buffer.len() => Err(std::io::Error::new(
+                ErrorKind::WriteZero, "The datagram was truncated"
+            )),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Sends a datagram from a single socket, bound on the first send and reused afterwards
+    fn send_udp(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        let socket = self.udp_socket()?;
+
+        let sent = socket.send(buffer);
+        self.socket = Some(socket);
+
+        Connection::sent_whole(sent, buffer)
+    }
+
+    /// Sends a datagram to the Unix domain socket. The socket is connected on the first send,
+    /// and again after a failed send, since the server may have recreated its socket.
+    #[cfg(unix)]
+    fn send_unix_datagram(&mut self, buffer: &[u8]) -> std::io::Result<()> {
+        let socket = match self.unix_socket.take() {
+            Some(socket) => socket,
+            None => self.connect(|connection| match &connection.destination {
+                Destination::Path(path) => {
+                    let socket = UnixDatagram::unbound()?;
+                    socket.connect(path)?;
+                    Ok(socket)
+                },
+                Destination::Host(_, _) => Err(std::io::Error::new(
+                    ErrorKind::InvalidInput, "Unix domain sockets require a path"
+                ))
+            })?
+        };
+
+        let sent = socket.send(buffer);
+        if sent.is_ok() {
+            self.backoff.succeeded();
+            self.unix_socket = Some(socket);
+        }
+
+        Connection::sent_whole(sent, buffer)
+    }
+
+    pub fn send(&mut self, buffer: &[u8]) -> Result<(), std::io::Error> {
+        match self.protocol {
+            NetworkProtocol::TCP => self.write_stream(buffer),
+            #[cfg(feature = "tls")]
+            NetworkProtocol::TLS => self.write_stream(buffer),
+            NetworkProtocol::UDP => self.send_udp(buffer),
+            #[cfg(unix)]
+            NetworkProtocol::UnixStream => self.write_stream(buffer),
+            #[cfg(unix)]
+            NetworkProtocol::UnixDatagram => self.send_unix_datagram(buffer)
+        }
     }
 }
 
@@ -59,40 +427,98 @@ impl Connection {
 #[cfg(test)]
 mod tests {
     mod udp {
-        use std::net::{UdpSocket, IpAddr, Ipv4Addr, };
+        use std::net::UdpSocket;
+        use std::time::Duration;
         use crate::connection::Connection;
         use crate::protocol::NetworkProtocol;
 
+        fn listen(addr: &str) -> std::io::Result<UdpSocket> {
+            let listener = UdpSocket::bind(addr)?;
+            listener.set_read_timeout(Some(Duration::from_secs(5)))?;
+            Ok(listener)
+        }
+
         #[test]
         pub fn it_should_send_data() -> Result<(), std::io::Error> {
             let data = b"Hello World!";
 
+            let udp_listener = listen("127.0.0.1:0")?;
             let mut connection = Connection::new(
-                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                3435,
+                "127.0.0.1".to_owned(),
+                udp_listener.local_addr()?.port(),
                 NetworkProtocol::UDP
             );
 
-            let udp_listener = UdpSocket::bind("127.0.0.1:3435")?;
-
             connection.send(&data[..])?;
             let mut buf: [u8; 256] = [0; 256];
             if let Ok(recieved) = udp_listener.recv(&mut buf) {
                 let buf = &mut buf[..recieved];
                 assert_eq!(buf, data);
-                println!("{:?}", buf);
             } else {
-                assert!(false, "Unable to receive data")
+                panic!("Unable to receive data")
             }
 
             Ok(())
         }
+
+        #[test]
+        pub fn it_should_reuse_one_socket() -> std::io::Result<()> {
+            let udp_listener = listen("127.0.0.1:0")?;
+            let mut connection = Connection::new(
+                "127.0.0.1".to_owned(), udp_listener.local_addr()?.port(), NetworkProtocol::UDP
+            );
+
+            let mut buf = [0; 256];
+            connection.send(b"First")?;
+            let (_, first) = udp_listener.recv_from(&mut buf)?;
+            connection.send(b"Second")?;
+            let (_, second) = udp_listener.recv_from(&mut buf)?;
+
+            assert_eq!(first, second);
+            assert_ne!(first.port(), udp_listener.local_addr()?.port());
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_send_to_ipv6_destinations() -> std::io::Result<()> {
+            // Skip on hosts without IPv6 loopback
+            let udp_listener = match listen("[::1]:0") {
+                Ok(listener) => listener,
+                Err(_) => return Ok(())
+            };
+            let mut connection = Connection::new(
+                "::1".to_owned(), udp_listener.local_addr()?.port(), NetworkProtocol::UDP
+            );
+
+            connection.send(b"Hello World!")?;
+            let mut buf = [0; 256];
+            let received = udp_listener.recv(&mut buf)?;
+            assert_eq!(&buf[..received], b"Hello World!");
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_report_send_errors() -> std::io::Result<()> {
+            let mut connection = Connection::new(
+                "127.0.0.1".to_owned(), 9, NetworkProtocol::UDP
+            );
+
+            assert!(connection.send(&vec![0; 70_000]).is_err());
+
+            Ok(())
+        }
     }
     mod tcp {
-        use std::net::{IpAddr, Ipv4Addr, TcpListener};
-        use super::super::Connection;
-        use crate::protocol::NetworkProtocol;
+        use std::net::TcpListener;
+        use super::super::{Backoff, Connection, INITIAL_BACKOFF, MAX_BACKOFF};
+        use crate::configuration::{local_test_configuration, ConfigurationBuilder};
+        use crate::metric::{MetricData, MetricType};
+        use crate::protocol::{Compression, NetworkProtocol, Protocol};
+        use crate::spool::SpoolOptions;
         use std::io::Read;
+        use std::time::{Duration, Instant};
 
         #[test]
         pub fn it_should_send_and_receive_data() -> std::io::Result<()> {
@@ -100,7 +526,7 @@ mod tests {
 
             let tcp_listener = TcpListener::bind("127.0.0.1:3435")?;
             let mut connection = Connection::new(
-                IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+                "127.0.0.1".to_owned(),
                 3435,
                 NetworkProtocol::TCP
             );
@@ -109,21 +535,296 @@ mod tests {
             connection.send(&data[..])?;
             let mut buf: [u8; 256] = [0; 256];
 
-            for stream in tcp_listener.incoming() {
-                let mut stream = stream?;
-                if let Ok(recieved) = stream.read(&mut buf) {
-                    let buf = &mut buf[..recieved];
-                    assert_eq!(buf, data);
-                } else {
-                    assert!(false, "Unable to receive data")
-                }
-
-                break;
+            let (mut stream, _) = tcp_listener.accept()?;
+            if let Ok(recieved) = stream.read(&mut buf) {
+                let buf = &mut buf[..recieved];
+                assert_eq!(buf, data);
+            } else {
+                panic!("Unable to receive data")
             }
 
             drop(connection);
 
             Ok(())
         }
+
+        fn unused_port() -> std::io::Result<u16> {
+            Ok(TcpListener::bind("127.0.0.1:0")?.local_addr()?.port())
+        }
+
+        fn read_line(listener: &TcpListener) -> std::io::Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            let mut buf = [0; 256];
+            let received = stream.read(&mut buf)?;
+            Ok(String::from_utf8_lossy(&buf[..received]).into_owned())
+        }
+
+        #[test]
+        pub fn it_should_not_panic_when_the_server_is_down() -> std::io::Result<()> {
+            let mut connection = Connection::new("127.0.0.1".to_owned(), unused_port()?, NetworkProtocol::TCP);
+
+            assert!(connection.send(b"Hello World!\n").is_err());
+            // Waiting to reconnect fails without another attempt
+            assert!(connection.send(b"Hello World!\n").is_err());
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_connect_once_the_server_is_up() -> std::io::Result<()> {
+            let port = unused_port()?;
+            let mut connection = Connection::new("127.0.0.1".to_owned(), port, NetworkProtocol::TCP);
+            assert!(connection.send(b"First\n").is_err());
+
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            std::thread::sleep(INITIAL_BACKOFF);
+
+            connection.send(b"Second\n")?;
+            assert_eq!(read_line(&listener)?, "Second\n");
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_reconnect_when_the_server_closes_the_connection() -> std::io::Result<()> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let mut connection = Connection::new(
+                "127.0.0.1".to_owned(), listener.local_addr()?.port(), NetworkProtocol::TCP
+            );
+
+            connection.send(b"First\n")?;
+            // The accepted stream is closed once the first line is read
+            assert_eq!(read_line(&listener)?, "First\n");
+            std::thread::sleep(Duration::from_millis(50));
+            assert_eq!(connection.reconnects(), 0);
+
+            connection.send(b"Second\n")?;
+            assert_eq!(read_line(&listener)?, "Second\n");
+            assert_eq!(connection.reconnects(), 1);
+
+            Ok(())
+        }
+
+        fn graphite(port: u16) -> ConfigurationBuilder {
+            local_test_configuration(port, Protocol::Graphite(Compression::Uncompressed), NetworkProtocol::TCP)
+        }
+
+        fn read_all(listener: &TcpListener) -> std::io::Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            let mut received = String::new();
+            stream.read_to_string(&mut received)?;
+            Ok(received)
+        }
+
+        fn record(name: &str) -> MetricData {
+            MetricData::new(None, name.to_owned(), 1, MetricType::Counter(1))
+        }
+
+        #[test]
+        pub fn it_should_count_reconnects() -> std::io::Result<()> {
+            let carbon = TcpListener::bind("127.0.0.1:0")?;
+            let mut configuration = graphite(carbon.local_addr()?.port()).build().unwrap();
+
+            configuration.send(vec![record("First")]);
+            drop(carbon.accept()?);
+            std::thread::sleep(Duration::from_millis(50));
+
+            configuration.send(vec![record("Second")]);
+            assert_eq!(configuration.telemetry().snapshot().reconnects, 1);
+
+            drop(configuration);
+            assert_eq!(read_all(&carbon)?, "Second 1 1\n");
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_resend_the_failed_batch_once_the_server_is_up() -> std::io::Result<()> {
+            let port = unused_port()?;
+            let mut configuration = graphite(port).build().unwrap();
+            configuration.send(vec![record("First")]);
+
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            std::thread::sleep(INITIAL_BACKOFF);
+
+            configuration.send(vec![record("Second")]);
+            drop(configuration);
+
+            assert_eq!(read_all(&listener)?, "First 1 1\nSecond 1 1\n");
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_count_payloads_dropped_for_a_newer_flush() -> std::io::Result<()> {
+            let mut configuration = graphite(unused_port()?).build().unwrap();
+
+            configuration.send(vec![record("First")]);
+            configuration.send(vec![record("Second")]);
+
+            let stats = configuration.telemetry().snapshot();
+            assert_eq!(stats.send_errors, 2);
+            assert_eq!(stats.payloads_dropped, 1);
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_count_spool_errors() -> std::io::Result<()> {
+            let directory = std::env::temp_dir().join(format!("metrical-spool-errors-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&directory);
+            let mut configuration = graphite(unused_port()?)
+                .spool(SpoolOptions::new(directory.clone()))
+                .build()
+                .unwrap();
+
+            // Segments cannot be created once the directory is replaced by a file
+            std::fs::remove_dir_all(&directory)?;
+            std::fs::write(&directory, b"")?;
+            configuration.send(vec![record("First")]);
+
+            let stats = configuration.telemetry().snapshot();
+            assert_eq!(stats.spool_errors, 1);
+            assert_eq!(stats.payloads_dropped, 1);
+
+            std::fs::remove_file(&directory)
+        }
+
+        #[test]
+        pub fn it_should_back_off_exponentially() {
+            let mut backoff = Backoff::new();
+            assert!(backoff.ready());
+            assert_eq!(backoff.backoff(), INITIAL_BACKOFF);
+
+            backoff.failed();
+            assert!(!backoff.ready());
+            assert_eq!(backoff.backoff(), INITIAL_BACKOFF * 2);
+
+            for _ in 0..40 {
+                backoff.failed();
+            }
+            assert_eq!(backoff.backoff(), MAX_BACKOFF);
+
+            let retry_in = backoff.retry_at.unwrap() - Instant::now();
+            assert!(retry_in <= MAX_BACKOFF && retry_in >= MAX_BACKOFF / 2 - Duration::from_secs(1));
+
+            backoff.succeeded();
+            assert!(backoff.ready());
+        }
+
+        #[test]
+        pub fn it_should_resolve_hostnames() -> std::io::Result<()> {
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let mut connection = Connection::new(
+                "localhost".to_owned(), listener.local_addr()?.port(), NetworkProtocol::TCP
+            );
+
+            connection.send(b"Hello World!\n")?;
+            assert_eq!(read_line(&listener)?, "Hello World!\n");
+
+            Ok(())
+        }
+
+        #[test]
+        pub fn it_should_report_unresolvable_hosts() {
+            let mut connection = Connection::new(
+                "metrical.invalid".to_owned(), 2003, NetworkProtocol::TCP
+            );
+
+            assert!(connection.send(b"Hello World!\n").is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    mod unix {
+        use std::io::Read;
+        use std::os::unix::net::{UnixDatagram, UnixListener};
+        use std::path::PathBuf;
+        use std::time::Duration;
+        use crate::connection::Connection;
+        use crate::protocol::NetworkProtocol;
+
+        fn socket_path(name: &str) -> PathBuf {
+            let path = std::env::temp_dir().join(format!("metrical-{}-{}.sock", name, std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            path
+        }
+
+        fn read_line(listener: &UnixListener) -> std::io::Result<String> {
+            let (mut stream, _) = listener.accept()?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            let mut buf = [0; 256];
+            let received = stream.read(&mut buf)?;
+            Ok(String::from_utf8_lossy(&buf[..received]).into_owned())
+        }
+
+        #[test]
+        pub fn it_should_send_datagrams() -> std::io::Result<()> {
+            let path = socket_path("datagram");
+            let server = UnixDatagram::bind(&path)?;
+            server.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+            let mut connection = Connection::unix(path.clone(), NetworkProtocol::UnixDatagram);
+            connection.send(b"Hello World!")?;
+
+            let mut buf = [0; 256];
+            let received = server.recv(&mut buf)?;
+            assert_eq!(&buf[..received], b"Hello World!");
+
+            std::fs::remove_file(&path)
+        }
+
+        #[test]
+        pub fn it_should_reconnect_datagrams_when_the_server_recreates_its_socket() -> std::io::Result<()> {
+            let path = socket_path("recreated");
+            let server = UnixDatagram::bind(&path)?;
+
+            let mut connection = Connection::unix(path.clone(), NetworkProtocol::UnixDatagram);
+            connection.send(b"First")?;
+
+            drop(server);
+            std::fs::remove_file(&path)?;
+            assert!(connection.send(b"Lost").is_err());
+
+            let server = UnixDatagram::bind(&path)?;
+            server.set_read_timeout(Some(Duration::from_secs(5)))?;
+            connection.send(b"Second")?;
+
+            let mut buf = [0; 256];
+            let received = server.recv(&mut buf)?;
+            assert_eq!(&buf[..received], b"Second");
+
+            std::fs::remove_file(&path)
+        }
+
+        #[test]
+        pub fn it_should_reconnect_streams() -> std::io::Result<()> {
+            let path = socket_path("stream");
+            let listener = UnixListener::bind(&path)?;
+
+            let mut connection = Connection::unix(path.clone(), NetworkProtocol::UnixStream);
+            connection.send(b"First\n")?;
+            assert_eq!(read_line(&listener)?, "First\n");
+            std::thread::sleep(Duration::from_millis(50));
+
+            connection.send(b"Second\n")?;
+            assert_eq!(read_line(&listener)?, "Second\n");
+
+            std::fs::remove_file(&path)
+        }
+
+        #[test]
+        pub fn it_should_report_missing_sockets() {
+            let mut connection = Connection::unix(socket_path("missing"), NetworkProtocol::UnixStream);
+            assert!(connection.send(b"Hello World!\n").is_err());
+
+            let mut connection = Connection::unix(socket_path("missing"), NetworkProtocol::UnixDatagram);
+            assert!(connection.send(b"Hello World!").is_err());
+        }
     }
 }
\ No newline at end of file
@@ -1,11 +1,14 @@
 use std::env::var;
-use crate::metric::Namespace;
-use crate::protocol::{NetworkProtocol, Protocol, Compression};
+use crate::protocol::{NetworkProtocol, Protocol, Compression, OpenTSDBMapping};
 use crate::MetricalError;
-use std::net::IpAddr;
+use std::net::Ipv6Addr;
+#[cfg(any(unix, feature = "tls"))]
+use std::path::PathBuf;
+#[cfg(feature = "tls")]
+use crate::tls::TlsOptions;
 use crate::configuration::{ConfigurationBuilder, Configuration};
-use crate::collector::BufferedCollector;
-use crate::shared::set_boxed_collector;
+use crate::client::MetricsClient;
+use crate::shared::set_client;
 
 static FLUSH_INTERVAL_ENV: &str = "METRICAL_FLUSH_INTERVAL";
 static FLUSH_AMOUNT_ENV: &str = "METRICAL_FLUSH_AMOUNT";
@@ -13,30 +16,60 @@ static NAMESPACE_ENV: &str = "METRICAL_NAMESPACE";
 static NETWORK_PROTOCOL_ENV: &str = "METRICAL_NETWORK_PROTOCOL";
 static SEND_METHOD_ENV: &str = "METRICAL_SEND_METHOD";
 static NETWORK_DESTINATION_ENV: &str = "METRICAL_NETWORK_DESTINATION";
+static OPENTSDB_PATH_TAGS_ENV: &str = "METRICAL_OPENTSDB_PATH_TAGS";
+static OPENTSDB_DEFAULT_TAGS_ENV: &str = "METRICAL_OPENTSDB_DEFAULT_TAGS";
+static MAX_PAYLOAD_SIZE_ENV: &str = "METRICAL_MAX_PAYLOAD_SIZE";
+#[cfg(feature = "tls")]
+static TLS_CA_FILE_ENV: &str = "METRICAL_TLS_CA_FILE";
+#[cfg(feature = "tls")]
+static TLS_CERT_FILE_ENV: &str = "METRICAL_TLS_CERT_FILE";
+#[cfg(feature = "tls")]
+static TLS_KEY_FILE_ENV: &str = "METRICAL_TLS_KEY_FILE";
+#[cfg(feature = "tls")]
+static TLS_SERVER_NAME_ENV: &str = "METRICAL_TLS_SERVER_NAME";
 
 /// Configure metrical by looking up environment variables.
 ///
 /// # Variables:
 /// The following environment variables are used:
 ///
-/// - METRICAL_FLUSH_INTERVAL: Specifies the interval (in milliseconds)
-/// to flush records to server. If unspecified, the library will not flush based on time since the last
-/// flush (Default: None).
+/// - METRICAL_FLUSH_INTERVAL: Specifies the interval (in seconds)
+///   to flush records to server. A background thread flushes the buffer on this interval, even when
+///   no new metrics are recorded. If unspecified, the library will not flush based on time since
+///   the last flush (Default: None).
 /// - METRICAL_FLUSH_AMOUNT: Specifies the amount of records to buffer before flushing records.
-/// If unspecified, the library will not limit the size of its buffer. (Default: None)
+///   If unspecified, the library will not limit the size of its buffer. (Default: None)
 ///
 /// If both METRICAL_FLUSH_INTERVAL and METRICAL_FLUSH_AMOUNT are unspecified, only manual flushing
 /// will send data to the server.
 ///
 /// - METRICAL_NAMESPACE: Specifies the namespace to place the metrics under.
-/// - METRICAL_NETWORK_PROTOCOL: Specifies the protocol \[UDP\|TCP\] to send the data as.
+/// - METRICAL_NETWORK_PROTOCOL: Specifies the protocol \[UDP\|TCP\|TLS\] to send the data as.
+///   TLS requires the `tls` feature.
+/// - METRICAL_MAX_PAYLOAD_SIZE: Specifies the maximum size in bytes of each packet or write.
+///   (Default: 1432 over UDP, 8192 over Unix datagrams, 65536 over streams)
 ///
 /// - METRICAL_SEND_METHOD: Specifies which method to send the data with. This can take on one of
-/// three values: Statsd, Graphite, Graphite_Pickle
+///   six values: Statsd, DogStatsd, Graphite, Graphite_Pickle, Influx_Line, OpenTSDB
 ///
-/// - METRICAL_NETWORK_DESTINATION: Specifies the destination to send the metrics to. For now, this is
-/// limited to an ip_address:port, or just an ip_address. If port is unspecified, a sane default is
-/// chosen, given the send method.
+/// - METRICAL_OPENTSDB_PATH_TAGS: A comma separated list of tag names to take from the leading
+///   segments of each path when sending to OpenTSDB, such as `env,host`.
+/// - METRICAL_OPENTSDB_DEFAULT_TAGS: A comma separated list of `key=value` tags added to every
+///   data point sent to OpenTSDB.
+///
+/// - METRICAL_TLS_CA_FILE: A PEM file of the certificates to verify the server against, instead
+///   of the Mozilla root certificates.
+/// - METRICAL_TLS_CERT_FILE and METRICAL_TLS_KEY_FILE: PEM files of the client certificate chain
+///   and private key to authenticate with.
+/// - METRICAL_TLS_SERVER_NAME: The name sent with SNI and verified against the server
+///   certificate, when it differs from the destination host.
+///
+/// - METRICAL_NETWORK_DESTINATION: Specifies the destination to send the metrics to, as a
+///   host:port, or just a host. The host is a hostname such as `statsd.internal` or an IP address;
+///   IPv6 addresses with a port are written in brackets, such as `[::1]:8125`. If port is
+///   unspecified, a sane default is chosen, given the send method.
+///   Unix domain sockets are written as `unix:///path/to/socket` for a stream, or
+///   `unixgram:///path/to/socket` for datagrams; METRICAL_NETWORK_PROTOCOL is not used for them.
 ///
 /// # Arguments
 /// - prefix - Specifies a prefix for the environment variables to look for.
@@ -46,6 +79,11 @@ static NETWORK_DESTINATION_ENV: &str = "METRICAL_NETWORK_DESTINATION";
 /// "MY_PROJECT_METRICAL_FLUSH_INTERVAL".
 ///
 pub fn from_env(prefix: Option<&str>) -> Result<(), MetricalError>{
+    from_config(configuration_from_env(prefix)?)
+}
+
+/// Builds the configuration described by the environment variables, see `from_env`
+fn configuration_from_env(prefix: Option<&str>) -> Result<Configuration, MetricalError> {
     let prefix = match prefix {
         Some("") => {
             "".to_owned()
@@ -59,40 +97,31 @@ pub fn from_env(prefix: Option<&str>) -> Result<(), MetricalError>{
     };
 
     let flush_interval: Option<u64> = match var(format!("{}{}", prefix, FLUSH_INTERVAL_ENV)) {
-        Ok(value) => match value.parse::<u64>() {
-            Ok(v) => Some(v),
-            Err(_) => None
-        },
+        Ok(value) => value.parse::<u64>().ok(),
         Err(_) => None
     };
 
     let flush_amount: Option<usize> = match var(format!("{}{}", prefix, FLUSH_AMOUNT_ENV)) {
-        Ok(value) => match value.parse::<usize>() {
-            Ok(v) => Some(v),
-            Err(_) => None
-        },
+        Ok(value) => value.parse::<usize>().ok(),
         Err(_) => None
     };
 
-    let namespace: Option<Namespace> = match var(format!("{}{}", prefix, NAMESPACE_ENV)) {
-        Ok(value) => Some(Namespace(value)),
-        Err(_) => None
-    };
+    let namespace: Option<String> = var(format!("{}{}", prefix, NAMESPACE_ENV)).ok();
 
-    let network_protocol: NetworkProtocol = match var(
-        format!("{}{}", prefix, NETWORK_PROTOCOL_ENV)
-    ) {
-        Ok(value) => match value.to_lowercase().as_ref() {
-            "udp" => NetworkProtocol::UDP,
-            "tcp" => NetworkProtocol::TCP,
-            _ => return Err(MetricalError::ConfigurationInvalid("Invalid value for network protocol environment variable"))
+    let max_payload_size: Option<usize> = match var(format!("{}{}", prefix, MAX_PAYLOAD_SIZE_ENV)) {
+        Ok(value) => match value.parse::<usize>() {
+            Ok(size) => Some(size),
+            Err(_) => return Err(MetricalError::ConfigurationInvalid("Unable to parse maximum payload size"))
         },
-        Err(_) => return Err(MetricalError::ConfigurationInvalid("No value for network protocol environment variable"))
+        Err(_) => None
     };
 
     let send_method: Protocol = match var(format!("{}{}", prefix, SEND_METHOD_ENV)) {
         Ok(val) => match val.to_lowercase().as_ref() {
             "statsd" => Protocol::StatsD,
+            "dogstatsd" => Protocol::DogStatsD,
+            "influx" | "influx_line" => Protocol::InfluxLine,
+            "opentsdb" => Protocol::OpenTSDB(opentsdb_mapping(&prefix)?),
             "graphite" => Protocol::Graphite(Compression::Uncompressed),
             #[cfg(feature = "pickle")]
             "graphite_pickle" => Protocol::Graphite(Compression::Pickled),
@@ -101,77 +130,279 @@ pub fn from_env(prefix: Option<&str>) -> Result<(), MetricalError>{
             )
         },
         Err(_) => return Err(
-            MetricalError::ConfigurationInvalid("Send Method unspecified [STATSD|GRAPHITE|GRAPHITE_PICKLE]")
+            MetricalError::ConfigurationInvalid("Send Method unspecified [STATSD|DOGSTATSD|GRAPHITE|GRAPHITE_PICKLE|INFLUX_LINE|OPENTSDB]")
         )
     };
 
-    let (ip_addr, port) = match var(
-        format!("{}{}", prefix, NETWORK_DESTINATION_ENV)
-    ) {
-        Ok(value) => {
-            match value.find(":") {
-                Some(idx) => {
-                    let ip_addr: IpAddr = match value[..idx].parse() {
-                        Ok(v) => v,
-                        Err(_) => return Err(
-                            MetricalError::ConfigurationInvalid("Unable to parse IP Address")
-                        )
-                    };
-
-                    let port: u16 = match value[idx + 1..].parse() {
-                        Ok(port) => port,
-                        Err(_) => return Err(
-                            MetricalError::ConfigurationInvalid("Unable to parse port")
-                        )
-                    };
-
-                    (ip_addr, port)
-                },
-                None => {
-                    let ip_addr: IpAddr = match value.parse() {
-                        Ok(v) => v,
-                        Err(_) => return Err(
-                            MetricalError::ConfigurationInvalid("Unable to parse IP Address")
-                        )
-                    };
-
-                    let port = match send_method {
-                        Protocol::StatsD => 8125,
-                        #[cfg(feature = "pickle")]
-                        Protocol::Graphite(Compression::Pickled) => 2004,
-                        Protocol::Graphite(Compression::Uncompressed) => 2003
-                    };
-
-                    (ip_addr, port)
-                }
-            }
-        },
+    let destination = match var(format!("{}{}", prefix, NETWORK_DESTINATION_ENV)) {
+        Ok(value) => value,
         Err(_) => return Err(
             MetricalError::ConfigurationInvalid("Network destination unspecified")
         )
     };
 
+    let builder = ConfigurationBuilder::new()
+        .namespace(namespace)
+        .flush_after_interval(flush_interval)
+        .flush_after_amount(flush_amount)
+        .max_payload_size(max_payload_size);
 
-    let configuration = ConfigurationBuilder::new()
-        .ip_addr(ip_addr)
+    #[cfg(unix)]
+    if let Some((network_protocol, path)) = unix_socket(&destination)? {
+        return builder
+            .protocol(send_method)
+            .network_protocol(network_protocol)
+            .socket_path(path)
+            .build();
+    }
+
+    let network_protocol: NetworkProtocol = match var(
+        format!("{}{}", prefix, NETWORK_PROTOCOL_ENV)
+    ) {
+        Ok(value) => match value.to_lowercase().as_ref() {
+            "udp" => NetworkProtocol::UDP,
+            "tcp" => NetworkProtocol::TCP,
+            #[cfg(feature = "tls")]
+            "tls" => NetworkProtocol::TLS,
+            _ => return Err(MetricalError::ConfigurationInvalid("Invalid value for network protocol environment variable"))
+        },
+        Err(_) => return Err(MetricalError::ConfigurationInvalid("No value for network protocol environment variable"))
+    };
+
+    let (host, port) = parse_destination(&destination)?;
+    let port = port.unwrap_or(match send_method {
+        Protocol::StatsD | Protocol::DogStatsD => 8125,
+        Protocol::InfluxLine => 8094,
+        Protocol::OpenTSDB(_) => 4242,
+        #[cfg(feature = "pickle")]
+        Protocol::Graphite(Compression::Pickled) => 2004,
+        Protocol::Graphite(Compression::Uncompressed) => 2003
+    });
+
+    #[cfg(feature = "tls")]
+    let builder = builder.tls(tls_options(&prefix)?);
+
+    builder
+        .host(host)
         .port(port)
-        .namespace(namespace)
         .protocol(send_method)
         .network_protocol(network_protocol)
-        .flush_after_interval(flush_interval)
-        .flush_after_amount(flush_amount)
-        .build()?;
+        .build()
+}
+
+/// Reads the TLS options from the environment
+#[cfg(feature = "tls")]
+fn tls_options(prefix: &str) -> Result<TlsOptions, MetricalError> {
+    let mut options = TlsOptions::new();
+
+    if let Ok(path) = var(format!("{}{}", prefix, TLS_CA_FILE_ENV)) {
+        options = options.ca_file(PathBuf::from(path));
+    }
 
-    from_config(configuration)
+    match (
+        var(format!("{}{}", prefix, TLS_CERT_FILE_ENV)),
+        var(format!("{}{}", prefix, TLS_KEY_FILE_ENV))
+    ) {
+        (Ok(certificate), Ok(key)) => {
+            options = options.client_certificate(PathBuf::from(certificate), PathBuf::from(key));
+        },
+        (Err(_), Err(_)) => {},
+        _ => return Err(
+            MetricalError::ConfigurationInvalid("TLS client certificates require both a certificate and a key file")
+        )
+    }
+
+    if let Ok(name) = var(format!("{}{}", prefix, TLS_SERVER_NAME_ENV)) {
+        options = options.server_name(name);
+    }
+
+    Ok(options)
+}
+
+/// The network protocol and path of a Unix domain socket destination, written as
+/// `unix:///path` for a stream or `unixgram:///path` for datagrams
+#[cfg(unix)]
+fn unix_socket(destination: &str) -> Result<Option<(NetworkProtocol, PathBuf)>, MetricalError> {
+    let (network_protocol, path) = if let Some(path) = destination.strip_prefix("unix://") {
+        (NetworkProtocol::UnixStream, path)
+    } else if let Some(path) = destination.strip_prefix("unixgram://") {
+        (NetworkProtocol::UnixDatagram, path)
+    } else {
+        return Ok(None);
+    };
+
+    if path.is_empty() {
+        return Err(MetricalError::ConfigurationInvalid("Unix domain socket path unspecified"));
+    }
+    Ok(Some((network_protocol, PathBuf::from(path))))
+}
+
+/// Splits a network destination into its host and port, if one is given.
+///
+/// The host is a hostname or an IP address. IPv6 addresses followed by a port must be
+/// enclosed in brackets, such as `[::1]:8125`.
+fn parse_destination(value: &str) -> Result<(String, Option<u16>), MetricalError> {
+    let parse_port = |port: &str| port.parse::<u16>().map_err(|_| {
+        MetricalError::ConfigurationInvalid("Unable to parse port")
+    });
+
+    let (host, port) = if let Some(bracketed) = value.strip_prefix('[') {
+        let (host, port) = match bracketed.split_once(']') {
+            Some((host, "")) => (host, None),
+            Some((host, port)) => match port.strip_prefix(':') {
+                Some(port) => (host, Some(parse_port(port)?)),
+                None => return Err(MetricalError::ConfigurationInvalid("Unable to parse port"))
+            },
+            None => return Err(MetricalError::ConfigurationInvalid("Unclosed bracket in network destination"))
+        };
+
+        if host.parse::<Ipv6Addr>().is_err() {
+            return Err(MetricalError::ConfigurationInvalid("Unable to parse IPv6 Address"));
+        }
+        (host, port)
+    } else if value.parse::<Ipv6Addr>().is_ok() {
+        (value, None)
+    } else {
+        match value.rsplit_once(':') {
+            Some((host, port)) => (host, Some(parse_port(port)?)),
+            None => (value, None)
+        }
+    };
+
+    if host.is_empty() {
+        return Err(MetricalError::ConfigurationInvalid("Network destination has no host"));
+    }
+    Ok((host.to_owned(), port))
+}
+
+/// Reads the OpenTSDB tag mapping from the environment
+fn opentsdb_mapping(prefix: &str) -> Result<OpenTSDBMapping, MetricalError> {
+    let mut mapping = OpenTSDBMapping::new();
+
+    if let Ok(value) = var(format!("{}{}", prefix, OPENTSDB_PATH_TAGS_ENV)) {
+        for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            mapping = mapping.path_tag(name.to_owned());
+        }
+    }
+
+    if let Ok(value) = var(format!("{}{}", prefix, OPENTSDB_DEFAULT_TAGS_ENV)) {
+        for tag in value.split(',').map(str::trim).filter(|tag| !tag.is_empty()) {
+            match tag.split_once('=') {
+                Some((key, value)) => {
+                    mapping = mapping.default_tag(key.to_owned(), value.to_owned());
+                },
+                None => return Err(
+                    MetricalError::ConfigurationInvalid("OpenTSDB default tags must be of the form key=value")
+                )
+            }
+        }
+    }
+
+    Ok(mapping)
 }
 
 /// Initializes the metrical library with the given configuration.
 ///
-/// You can create a configuration via the `ConfigurationBuilder` class.
+/// You can create a configuration via the `ConfigurationBuilder` class. Metrical can only be
+/// initialized once per process; subsequent calls return `MetricalError::AlreadyInitialized`.
 pub fn from_config(configuration: Configuration) -> Result<(), MetricalError> {
-    let collector = Box::new(BufferedCollector::new(configuration));
+    set_client(MetricsClient::new(configuration))
+}
+
+/// Initializes the metrical library to serve metrics to Prometheus, rather than pushing them
+/// to a server.
+///
+/// An embedded HTTP listener bound to `addr` answers scrapes of `/metrics` in the Prometheus
+/// text exposition format. Counters report cumulative totals, gauges their current value, and
+/// timers and histograms are reported as Prometheus histograms, timers in seconds.
+#[cfg(feature = "prometheus")]
+pub fn from_prometheus(addr: std::net::SocketAddr, namespace: Option<String>) -> Result<(), MetricalError> {
+    set_client(MetricsClient::prometheus(addr, namespace)?)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::init::parse_destination;
+
+    #[test]
+    pub fn it_should_parse_hosts_and_ports() {
+        assert_eq!(parse_destination("127.0.0.1:8125").ok(), Some(("127.0.0.1".to_owned(), Some(8125))));
+        assert_eq!(parse_destination("127.0.0.1").ok(), Some(("127.0.0.1".to_owned(), None)));
+        assert_eq!(parse_destination("statsd.internal:8125").ok(), Some(("statsd.internal".to_owned(), Some(8125))));
+        assert_eq!(parse_destination("statsd.internal").ok(), Some(("statsd.internal".to_owned(), None)));
+    }
+
+    #[test]
+    pub fn it_should_parse_ipv6_addresses() {
+        assert_eq!(parse_destination("[::1]:8125").ok(), Some(("::1".to_owned(), Some(8125))));
+        assert_eq!(parse_destination("[::1]").ok(), Some(("::1".to_owned(), None)));
+        assert_eq!(parse_destination("fe80::1").ok(), Some(("fe80::1".to_owned(), None)));
+    }
+
+    #[test]
+    pub fn it_should_reject_malformed_destinations() {
+        assert!(parse_destination("[::1").is_err());
+        assert!(parse_destination("[::1]8125").is_err());
+        assert!(parse_destination("[statsd]:8125").is_err());
+        assert!(parse_destination("statsd.internal:port").is_err());
+        assert!(parse_destination(":8125").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    pub fn it_should_parse_unix_socket_destinations() {
+        use crate::init::unix_socket;
+        use crate::protocol::NetworkProtocol;
+        use std::path::PathBuf;
+
+        match unix_socket("unix:///var/run/statsd.sock") {
+            Ok(Some((NetworkProtocol::UnixStream, path))) => assert_eq!(path, PathBuf::from("/var/run/statsd.sock")),
+            _ => panic!("Expected a Unix stream destination")
+        }
+        match unix_socket("unixgram:///var/run/statsd.sock") {
+            Ok(Some((NetworkProtocol::UnixDatagram, path))) => assert_eq!(path, PathBuf::from("/var/run/statsd.sock")),
+            _ => panic!("Expected a Unix datagram destination")
+        }
+        assert!(matches!(unix_socket("statsd.internal:8125"), Ok(None)));
+        assert!(unix_socket("unix://").is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    pub fn it_should_configure_tls_from_the_environment() {
+        use crate::client::MetricsClient;
+        use crate::init::configuration_from_env;
+        use crate::tls::test::{certificate, serve};
+
+        let server = certificate("server-env");
+        let (port, handle) = serve(&server, None);
+
+        std::env::set_var("ENV_TLS_TEST_METRICAL_NETWORK_PROTOCOL", "TLS");
+        std::env::set_var("ENV_TLS_TEST_METRICAL_NETWORK_DESTINATION", format!("127.0.0.1:{}", port));
+        std::env::set_var("ENV_TLS_TEST_METRICAL_SEND_METHOD", "Graphite");
+        std::env::set_var("ENV_TLS_TEST_METRICAL_TLS_CA_FILE", &server.0);
+        std::env::set_var("ENV_TLS_TEST_METRICAL_TLS_SERVER_NAME", "localhost");
+
+        let client = MetricsClient::new(configuration_from_env(Some("ENV_TLS_TEST")).unwrap());
+
+        let mut counter = client.counter("HelloCounter".to_owned());
+        counter.increment(1);
+        drop(counter);
+        client.flush();
+
+        let line = handle.join().unwrap().unwrap();
+        assert!(line.starts_with("HelloCounter 1 "), "{}", line);
+    }
+
+    #[test]
+    #[cfg(feature = "tls")]
+    pub fn it_should_require_both_a_client_certificate_and_key() {
+        use crate::init::tls_options;
 
-    set_boxed_collector(collector);
+        std::env::set_var("ENV_TLS_KEY_TEST_METRICAL_TLS_CERT_FILE", "/etc/carbon/client.pem");
+        assert!(tls_options("ENV_TLS_KEY_TEST_").is_err());
 
-    Ok(())
+        std::env::set_var("ENV_TLS_KEY_TEST_METRICAL_TLS_KEY_FILE", "/etc/carbon/client.key");
+        assert!(tls_options("ENV_TLS_KEY_TEST_").is_ok());
+    }
 }
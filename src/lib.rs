@@ -1,30 +1,52 @@
 //! Metrical is a library for quickly exporting metrics to Graphite or StatsD.
 //!
-//! Metrical supports 3 different protocols for sending data:
+//! Metrical supports 6 different protocols for sending data:
 //! - Statsd
+//! - DogStatsD (Statsd with tags)
 //! - Graphite Uncompressed
 //! - Graphite Compressed (Pickled)
+//! - InfluxDB line protocol
+//! - OpenTSDB telnet protocol
 //!
 //! Additionally, it supports sending via TCP or UDP.
 //!
+//! The free functions in this crate report to a single process wide client. To send metrics
+//! from different parts of a program to different destinations, create a `MetricsClient`
+//! for each destination instead.
+//!
+//! A single configuration can also send every metric to several destinations, each with its
+//! own protocol and transport, see `ConfigurationBuilder::sink`.
+//!
+//! Metrics recorded before metrical is configured with `from_env` or `from_config` are
+//! discarded, so libraries can be instrumented without forcing every binary to configure
+//! metrical.
+//!
 //! # Features
 //! Metrical exports the following features:
 //! - **pickle** - This feature is required to use the graphite pickled protocol.
-//! This features is not on by default. The protocol will pickle using
-//! pickle version 3.
+//!   This features is not on by default. The protocol will pickle using
+//!   pickle version 3.
+//! - **tls** - This feature adds `NetworkProtocol::TLS`, a TLS encrypted TCP transport with
+//!   support for custom CA bundles, client certificates and SNI. This feature is not on by
+//!   default.
+//! - **prometheus** - This feature serves metrics to Prometheus from an embedded HTTP listener,
+//!   see `from_prometheus`. This feature is not on by default.
 mod metric;
-pub use metric::{Counter, Timer, Gauge};
-use crate::metric::Namespace;
+pub use metric::{Counter, Timer, Gauge, Set, Histogram, Namespace};
 use std::error::Error;
 use std::fmt::Display;
 use std::fmt::Formatter;
 
 /// Describes an error associated with the metrical library.
 ///
-/// Currently the only metrical error that can occur is `ConfigurationInvalid`
+/// - `ConfigurationInvalid` is returned when a configuration cannot be built.
+/// - `AlreadyInitialized` is returned when metrical is configured more than once.
+/// - `Io` is returned when a socket metrical needs cannot be opened.
 #[derive(Debug)]
 pub enum MetricalError {
-    ConfigurationInvalid(&'static str)
+    ConfigurationInvalid(&'static str),
+    AlreadyInitialized,
+    Io(std::io::Error)
 }
 
 impl Display for MetricalError {
@@ -32,6 +54,12 @@ impl Display for MetricalError {
         match self {
             MetricalError::ConfigurationInvalid(msg) => {
                 f.write_str(format!("Configuration Error: {}", msg).as_str())
+            },
+            MetricalError::AlreadyInitialized => {
+                f.write_str("Metrical has already been initialized")
+            },
+            MetricalError::Io(err) => {
+                f.write_str(format!("IO Error: {}", err).as_str())
             }
         }
     }
@@ -40,13 +68,30 @@ impl Display for MetricalError {
 impl Error for MetricalError {}
 
 mod connection;
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "tls")]
+pub use tls::TlsOptions;
 mod protocol;
+mod aggregate;
+mod sharding;
+mod spool;
+pub use spool::SpoolOptions;
+pub use protocol::{Protocol, NetworkProtocol, Compression, OpenTSDBMapping};
 mod configuration;
-pub use configuration::{Configuration, ConfigurationBuilder};
+pub use configuration::{Configuration, ConfigurationBuilder, OverflowPolicy};
 mod collector;
+mod stats;
+pub use stats::Stats;
+mod client;
+pub use client::MetricsClient;
+#[cfg(feature = "prometheus")]
+mod prometheus;
 mod shared;
 mod init;
 pub use init::{from_env, from_config};
+#[cfg(feature = "prometheus")]
+pub use init::from_prometheus;
 
 /// Create a counter to count a metric that always increases.
 ///
@@ -63,16 +108,7 @@ pub use init::{from_env, from_config};
 /// }
 /// ```
 pub fn counter(name: String) -> metric::Counter {
-    let mut ctor = metric::CounterBuilder::new(name);
-
-    match shared::collector().namespace() {
-        Some(Namespace(path)) => {
-            ctor.namespace(Some(path)).build()
-        },
-        None => {
-            ctor.build()
-        }
-    }
+    shared::client().counter(name)
 }
 
 
@@ -106,16 +142,7 @@ pub fn counter(name: String) -> metric::Counter {
 /// }
 /// ```
 pub fn gauge(name: String) -> metric::Gauge {
-    let mut ctor = metric::GaugeBuilder::new(name);
-
-    match shared::collector().namespace() {
-        Some(Namespace(path)) => {
-            ctor.namespace(Some(path)).build()
-        },
-        None => {
-            ctor.build()
-        }
-    }
+    shared::client().gauge(name)
 }
 
 /// Create a timer to monitor time needed to perform a specific action
@@ -134,16 +161,51 @@ pub fn gauge(name: String) -> metric::Gauge {
 /// }
 /// ```
 pub fn timer(name: String) -> metric::Timer {
-    let mut ctor = metric::TimerBuilder::new(name);
+    shared::client().timer(name)
+}
 
-    match shared::collector().namespace() {
-        Some(Namespace(path)) => {
-            ctor.namespace(Some(path)).build()
-        },
-        None => {
-            ctor.build()
-        }
-    }
+/// Create a set to count the unique values seen during each flush interval
+///
+/// # Example
+/// ```
+/// use metrical::set;
+///
+/// pub fn login(user_name: &str, session_id: u64) {
+///     let mut users = set("UniqueUsers".to_owned());
+///     users.insert(user_name);
+///
+///     let mut sessions = set("UniqueSessions".to_owned());
+///     sessions.insert(session_id);
+/// }
+/// ```
+pub fn set(name: String) -> metric::Set {
+    shared::client().set(name)
+}
+
+/// Create a histogram to measure the distribution of arbitrary values
+///
+/// Histograms are published to StatsD with the `|h` type. When publishing to Graphite,
+/// the values of each histogram are summarized client side every flush.
+///
+/// # Example
+/// ```
+/// use metrical::histogram;
+///
+/// pub fn receive(payload: &[u8]) {
+///     let mut sizes = histogram("PayloadSize".to_owned());
+///     sizes.record(payload.len() as f64);
+/// }
+/// ```
+pub fn histogram(name: String) -> metric::Histogram {
+    shared::client().histogram(name)
+}
+
+/// Create a DogStatsD distribution to measure the distribution of arbitrary values
+///
+/// Distributions behave like histograms, but are published to StatsD with the `|d` type
+/// so they are aggregated globally by DogStatsD.
+pub fn distribution(name: String) -> metric::Histogram {
+    shared::client().distribution(name)
 }
 
 ///  Extend the namespace used by created metrics
@@ -163,15 +225,7 @@ pub fn timer(name: String) -> metric::Timer {
 /// ```
 ///
 pub fn namespace(namespace: String) -> metric::Namespace {
-    let global_namespace = shared::collector().namespace();
-    match global_namespace {
-        Some(Namespace(path)) => {
-            Namespace::new(format!("{}.{}", path, namespace))
-        },
-        None => {
-            Namespace::new(namespace)
-        }
-    }
+    shared::client().namespace(namespace)
 }
 
 /// Force any buffered metrics to be published.
@@ -179,7 +233,7 @@ pub fn namespace(namespace: String) -> metric::Namespace {
 /// It is a good idea to execute this at the end of your program.
 ///
 /// # Example
-/// ```
+/// ```no_run
 /// use metrical::{from_env, counter, flush};
 ///
 /// pub fn main() {
@@ -195,5 +249,25 @@ pub fn namespace(namespace: String) -> metric::Namespace {
 /// }
 /// ```
 pub fn flush() {
-    shared::collector().flush();
+    shared::client().flush();
+}
+
+/// The number of metrics discarded because the buffer was full, see
+/// `ConfigurationBuilder::max_buffered_records`
+pub fn dropped() -> u64 {
+    shared::client().dropped()
+}
+
+/// A snapshot of metrical's own activity: metrics buffered, flushed and dropped, payloads
+/// and bytes sent, send errors, reconnects and flush latency.
+///
+/// # Example
+/// ```
+/// let stats = metrical::stats();
+/// if stats.send_errors > 0 {
+///     eprintln!("Unable to send {} payloads", stats.send_errors);
+/// }
+/// ```
+pub fn stats() -> Stats {
+    shared::client().stats()
 }
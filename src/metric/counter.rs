@@ -1,27 +1,38 @@
 use crate::metric::{MetricData, MetricGenerator, MetricType};
 use std::time::{SystemTime, UNIX_EPOCH};
-use crate::shared::collector;
+use crate::collector::Collector;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
 
 pub struct CounterBuilder {
     name: String,
-    namespace: Option<String>
+    namespace: Option<String>,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
 }
 
 impl CounterBuilder {
-    pub fn new(name: String) -> CounterBuilder {
-        CounterBuilder{ name, namespace: Option::None }
+    pub fn new(name: String, collector: Arc<dyn Collector>) -> CounterBuilder {
+        CounterBuilder{ name, namespace: Option::None, tags: Vec::new(), collector }
     }
 
-    pub fn namespace(&mut self, namespace: Option<String>) -> &Self {
+    pub fn namespace(&mut self, namespace: Option<String>) -> &mut Self {
         self.namespace = namespace;
         self
     }
 
+    pub fn tags(&mut self, tags: Vec<(String, String)>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+
     pub fn build(&self) -> Counter {
         Counter {
             count: 0,
             name: self.name.clone(),
-            namespace: self.namespace.clone()
+            namespace: self.namespace.clone(),
+            tags: self.tags.clone(),
+            collector: self.collector.clone()
         }
     }
 }
@@ -29,19 +40,37 @@ impl CounterBuilder {
 /// A metric used to measure incrementing values.
 ///
 /// Counters can be created via the `metrical::counter` function,
-/// or the `metrical::namespace::counter` method.
+/// the `MetricsClient::counter` method or the `Namespace::counter` method.
 ///
 /// # Examples
 /// - The number of login attempts you've handled
 /// - The number of cache lookups you've successfully made
-#[derive(Debug)]
 pub struct Counter {
     count: u32,
     name: String,
-    namespace: Option<String>
+    namespace: Option<String>,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
+}
+
+impl Debug for Counter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Counter")
+            .field("count", &self.count)
+            .field("name", &self.name)
+            .field("namespace", &self.namespace)
+            .field("tags", &self.tags)
+            .finish()
+    }
 }
 
 impl Counter {
+    /// Attach a tag to this metric. Tags are published by protocols which support them.
+    pub fn tag(&mut self, key: String, value: String) -> &mut Self {
+        self.tags.push((key, value));
+        self
+    }
+
     /// Increment the value of a counter by the given amount
     pub fn increment(&mut self, amount: u32) -> &Self {
         self.count += amount;
@@ -51,23 +80,24 @@ impl Counter {
 
 impl Drop for Counter {
     fn drop(&mut self) {
-        collector().send(self.metric());
+        self.collector.send(self.metric());
     }
 }
 
 impl MetricGenerator for Counter {
     fn metric(&self) -> MetricData {
-        let namespace = self.namespace.clone().take();
+        let namespace = self.namespace.clone();
         let name = self.name.clone();
 
         let occurred = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .map_or(0, |d| d.as_secs());
+            .unwrap_or_default();
 
         MetricData{
             namespace,
             name,
             occurred,
+            tags: self.tags.clone(),
             metric: MetricType::Counter(self.count)
         }
     }
@@ -79,10 +109,12 @@ mod test {
     mod counter {
         use crate::metric::{MetricGenerator, MetricData, MetricType};
         use crate::metric::counter::CounterBuilder;
+        use crate::collector::NullCollector;
+        use std::sync::Arc;
 
         #[test]
         pub fn test_increment() {
-            let mut counter = CounterBuilder::new("HelloCounter".to_owned())
+            let mut counter = CounterBuilder::new("HelloCounter".to_owned(), Arc::new(NullCollector))
                 .namespace(Option::None)
                 .build();
 
@@ -96,6 +128,7 @@ mod test {
                 MetricData {
                     namespace: Option::None,
                     occurred: result.occurred,
+                    tags: Vec::new(),
                     name: "HelloCounter".to_owned(),
                     metric: MetricType::Counter(2)
                 }
@@ -106,7 +139,7 @@ mod test {
 
         #[test]
         pub fn it_should_work_after_many_additions() {
-            let mut counter = CounterBuilder::new("HelloCounter".to_owned())
+            let mut counter = CounterBuilder::new("HelloCounter".to_owned(), Arc::new(NullCollector))
                 .namespace(Option::None)
                 .build();
 
@@ -120,6 +153,7 @@ mod test {
                 MetricData{
                     namespace: Option::None,
                     occurred: result.occurred,
+                    tags: Vec::new(),
                     name: "HelloCounter".to_owned(),
                     metric: MetricType::Counter(4950)
                 }
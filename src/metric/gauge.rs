@@ -1,6 +1,7 @@
 use crate::metric::{MetricGenerator, MetricData, MetricType};
 use std::time::SystemTime;
-use crate::shared::collector;
+use crate::collector::Collector;
+use std::sync::Arc;
 use std::ops::{Add, AddAssign};
 use std::fmt::Display;
 use std::fmt::Formatter;
@@ -8,27 +9,38 @@ use std::fmt::Formatter;
 
 pub struct GaugeBuilder {
     name: String,
-    namespace: Option<String>
+    namespace: Option<String>,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
 }
 
 impl GaugeBuilder {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, collector: Arc<dyn Collector>) -> Self {
         GaugeBuilder{
             name,
-            namespace: Option::None
+            namespace: Option::None,
+            tags: Vec::new(),
+            collector
         }
     }
 
-    pub fn namespace(&mut self, namespace: Option<String>) -> &Self {
+    pub fn namespace(&mut self, namespace: Option<String>) -> &mut Self {
         self.namespace = namespace;
         self
     }
 
+    pub fn tags(&mut self, tags: Vec<(String, String)>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+
     pub fn build(&self) -> Gauge {
         Gauge{
             name: self.name.clone(),
             namespace: self.namespace.clone(),
-            value: GaugeOptions::Increase(0)
+            tags: self.tags.clone(),
+            value: GaugeOptions::Increase(0),
+            collector: self.collector.clone()
         }
     }
 }
@@ -43,7 +55,9 @@ impl GaugeBuilder {
 pub struct Gauge {
     name: String,
     namespace: Option<String>,
-    value: GaugeOptions
+    value: GaugeOptions,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -53,6 +67,8 @@ pub enum GaugeOptions {
     Set(u32)
 }
 
+/// Combines a later change into this one. Values saturate at the bounds of a `u32`, so
+/// changes summed over a long flush window never overflow.
 impl Add for GaugeOptions {
     type Output = Self;
 
@@ -60,7 +76,7 @@ impl Add for GaugeOptions {
         match self {
             GaugeOptions::Increase(v) => {
                 match rhs {
-                    GaugeOptions::Increase(v2) => GaugeOptions::Increase(v + v2),
+                    GaugeOptions::Increase(v2) => GaugeOptions::Increase(v.saturating_add(v2)),
                     GaugeOptions::Decrease(v2) => if v2 > v {
                         GaugeOptions::Decrease(v2 - v)
                     } else {
@@ -76,13 +92,13 @@ impl Add for GaugeOptions {
                     } else {
                         GaugeOptions::Decrease(v - v2)
                     },
-                    GaugeOptions::Decrease(v2) => GaugeOptions::Decrease(v2 + v),
+                    GaugeOptions::Decrease(v2) => GaugeOptions::Decrease(v.saturating_add(v2)),
                     GaugeOptions::Set(v2) => GaugeOptions::Set(v2)
                 }
             },
             GaugeOptions::Set(v) => {
                 match rhs {
-                    GaugeOptions::Increase(v2) => GaugeOptions::Set(v + v2),
+                    GaugeOptions::Increase(v2) => GaugeOptions::Set(v.saturating_add(v2)),
                     GaugeOptions::Decrease(v2) => if v2 > v {
                         GaugeOptions::Set(0)
                     } else {
@@ -113,6 +129,12 @@ impl Display for GaugeOptions {
 }
 
 impl Gauge {
+    /// Attach a tag to this metric. Tags are published by protocols which support them.
+    pub fn tag(&mut self, key: String, value: String) -> &mut Self {
+        self.tags.push((key, value));
+        self
+    }
+
     /// Increment the value of a gauge
     pub fn increment(&mut self, value: u32) {
         self.value += GaugeOptions::Increase(value);
@@ -128,7 +150,7 @@ impl Gauge {
         self.value = GaugeOptions::Set(value);
 
         // Set values are sent right away
-        collector().send(self.metric());
+        self.collector.send(self.metric());
         self.value = GaugeOptions::Increase(0);
     }
 }
@@ -137,12 +159,13 @@ impl MetricGenerator for Gauge {
     fn metric(&self) -> MetricData {
         let occurred = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
-            .map_or(0, |d| d.as_secs());
+            .unwrap_or_default();
 
         MetricData {
             namespace: self.namespace.clone(),
             name: self.name.clone(),
             occurred,
+            tags: self.tags.clone(),
             metric: MetricType::Gauge(self.value.clone())
         }
     }
@@ -150,7 +173,7 @@ impl MetricGenerator for Gauge {
 
 impl Drop for Gauge {
     fn drop(&mut self) {
-        collector().send(self.metric());
+        self.collector.send(self.metric());
     }
 }
 
@@ -158,10 +181,12 @@ impl Drop for Gauge {
 mod test {
     use crate::metric::gauge::{GaugeBuilder, GaugeOptions};
     use crate::metric::{MetricGenerator, MetricData, MetricType};
+    use crate::collector::NullCollector;
+    use std::sync::Arc;
 
     #[test]
     pub fn it_should_go_up_and_down() {
-        let mut gauge = GaugeBuilder::new("HelloGauge".to_owned())
+        let mut gauge = GaugeBuilder::new("HelloGauge".to_owned(), Arc::new(NullCollector))
             .namespace(Option::None)
             .build();
 
@@ -171,6 +196,7 @@ mod test {
         assert_eq!(metric, MetricData {
             namespace: Option::None,
             occurred: metric.occurred,
+            tags: Vec::new(),
             name: "HelloGauge".to_owned(),
             metric: MetricType::Gauge(GaugeOptions::Increase(1))
         });
@@ -181,6 +207,7 @@ mod test {
         assert_eq!(metric, MetricData {
             namespace: Option::None,
             occurred: metric.occurred,
+            tags: Vec::new(),
             name: "HelloGauge".to_owned(),
             metric: MetricType::Gauge(GaugeOptions::Increase(0))
         });
@@ -191,10 +218,23 @@ mod test {
         assert_eq!(metric, MetricData {
             namespace: Option::None,
             occurred: metric.occurred,
+            tags: Vec::new(),
             name: "HelloGauge".to_owned(),
             metric: MetricType::Gauge(GaugeOptions::Decrease(1))
         });
 
         std::mem::forget(gauge);
     }
+
+    #[test]
+    pub fn it_should_saturate_instead_of_overflowing() {
+        assert_eq!(GaugeOptions::Increase(u32::MAX) + GaugeOptions::Increase(1), GaugeOptions::Increase(u32::MAX));
+        assert_eq!(GaugeOptions::Decrease(u32::MAX) + GaugeOptions::Decrease(1), GaugeOptions::Decrease(u32::MAX));
+        assert_eq!(GaugeOptions::Set(u32::MAX) + GaugeOptions::Increase(1), GaugeOptions::Set(u32::MAX));
+
+        let mut merged = MetricData::new(None, "HelloGauge".to_owned(), 1, MetricType::Gauge(GaugeOptions::Increase(u32::MAX)));
+        let later = MetricData::new(None, "HelloGauge".to_owned(), 2, MetricType::Gauge(GaugeOptions::Increase(u32::MAX)));
+        assert!(merged.merge(later).is_ok());
+        assert_eq!(merged.metric(), &MetricType::Gauge(GaugeOptions::Increase(u32::MAX)));
+    }
 }
\ No newline at end of file
@@ -0,0 +1,159 @@
+use crate::metric::{MetricData, MetricType};
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::collector::Collector;
+use std::sync::Arc;
+
+/// How a histogram is aggregated by a StatsD server.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum HistogramKind {
+    /// Aggregated by each StatsD server (`|h`)
+    Histogram,
+    /// Aggregated globally by DogStatsD (`|d`)
+    Distribution
+}
+
+pub struct HistogramBuilder {
+    name: String,
+    namespace: Option<String>,
+    kind: HistogramKind,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
+}
+
+impl HistogramBuilder {
+    pub fn new(name: String, collector: Arc<dyn Collector>) -> HistogramBuilder {
+        HistogramBuilder{ name, namespace: Option::None, tags: Vec::new(), kind: HistogramKind::Histogram, collector }
+    }
+
+    pub fn namespace(&mut self, namespace: Option<String>) -> &mut Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn tags(&mut self, tags: Vec<(String, String)>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn kind(&mut self, kind: HistogramKind) -> &mut Self {
+        self.kind = kind;
+        self
+    }
+
+    pub fn build(&self) -> Histogram {
+        Histogram {
+            values: Vec::new(),
+            name: self.name.clone(),
+            namespace: self.namespace.clone(),
+            tags: self.tags.clone(),
+            kind: self.kind,
+            collector: self.collector.clone()
+        }
+    }
+}
+
+/// A metric used to measure the distribution of arbitrary values.
+///
+/// Histograms can be created via the `metrical::histogram` and `metrical::distribution`
+/// functions, or the matching methods on `MetricsClient` and `Namespace`.
+///
+/// When publishing to Graphite, the values of each histogram are summarized client side
+/// each time the buffer is flushed.
+///
+/// # Examples
+/// - The size of payloads received by a service
+/// - The depth of a queue when work is submitted
+pub struct Histogram {
+    values: Vec<f64>,
+    name: String,
+    namespace: Option<String>,
+    kind: HistogramKind,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
+}
+
+impl Histogram {
+    /// Attach a tag to this metric. Tags are published by protocols which support them.
+    pub fn tag(&mut self, key: String, value: String) -> &mut Self {
+        self.tags.push((key, value));
+        self
+    }
+
+    /// Record a value in the histogram
+    pub fn record(&mut self, value: f64) -> &Self {
+        self.values.push(value);
+        self
+    }
+
+    /// The records describing each recorded value
+    pub fn metrics(&self) -> Vec<MetricData> {
+        let occurred = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.values.iter().map(|value| MetricData {
+            namespace: self.namespace.clone(),
+            name: self.name.clone(),
+            occurred,
+            tags: self.tags.clone(),
+            metric: match self.kind {
+                HistogramKind::Histogram => MetricType::Histogram(*value),
+                HistogramKind::Distribution => MetricType::Distribution(*value)
+            }
+        }).collect()
+    }
+}
+
+impl Drop for Histogram {
+    fn drop(&mut self) {
+        for metric in self.metrics() {
+            self.collector.send(metric);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metric::histogram::{HistogramBuilder, HistogramKind};
+    use crate::metric::MetricType;
+    use crate::collector::NullCollector;
+    use std::sync::Arc;
+
+    #[test]
+    pub fn it_should_record_every_value() {
+        let mut histogram = HistogramBuilder::new("HelloHistogram".to_owned(), Arc::new(NullCollector))
+            .build();
+
+        histogram.record(12.0);
+        histogram.record(0.5);
+        histogram.record(12.0);
+
+        let result: Vec<MetricType> = histogram.metrics()
+            .into_iter()
+            .map(|metric| metric.metric)
+            .collect();
+
+        assert_eq!(result, vec![
+            MetricType::Histogram(12.0),
+            MetricType::Histogram(0.5),
+            MetricType::Histogram(12.0)
+        ]);
+
+        std::mem::forget(histogram);
+    }
+
+    #[test]
+    pub fn it_should_record_distributions() {
+        let mut histogram = HistogramBuilder::new("HelloDistribution".to_owned(), Arc::new(NullCollector))
+            .kind(HistogramKind::Distribution)
+            .build();
+
+        histogram.record(3.0);
+
+        let result = histogram.metrics();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].metric, MetricType::Distribution(3.0));
+
+        std::mem::forget(histogram);
+    }
+}
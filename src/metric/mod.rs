@@ -1,16 +1,34 @@
+use crate::collector::Collector;
+use std::sync::Arc;
+use std::time::Duration;
+
 pub trait MetricGenerator {
     fn metric(&self) -> MetricData;
 }
 
+/// The path and tags identifying a series
+pub type Series = (String, Vec<(String, String)>);
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct MetricData {
     namespace: Option<String>,
     name: String,
-    occurred: u64,
+    occurred: Duration,
+    tags: Vec<(String, String)>,
     metric: MetricType
 }
 
 impl MetricData {
+    pub fn new(namespace: Option<String>, name: String, occurred: u64, metric: MetricType) -> MetricData {
+        MetricData { namespace, name, occurred: Duration::from_secs(occurred), tags: Vec::new(), metric }
+    }
+
+    /// A copy of this record carrying the given tags
+    pub fn with_tags(mut self, tags: Vec<(String, String)>) -> MetricData {
+        self.tags = tags;
+        self
+    }
+
     pub fn path(&self) -> String {
         self.namespace.clone().map_or_else(
             || self.name.clone(),
@@ -19,65 +37,244 @@ impl MetricData {
     }
 
     pub fn metric(&self) -> &MetricType {
-        return &self.metric
+        &self.metric
     }
 
+    /// The time the record occurred, in seconds since the Unix epoch
     pub fn occurred(&self) -> u64 {
-        return self.occurred
+        self.occurred.as_secs()
+    }
+
+    /// The time the record occurred, in nanoseconds since the Unix epoch
+    pub fn occurred_nanos(&self) -> u128 {
+        self.occurred.as_nanos()
+    }
+
+    /// The tags of this record, as key value pairs
+    pub fn tags(&self) -> &[(String, String)] {
+        &self.tags
+    }
+
+    /// The memory used by this record, approximately, in bytes
+    pub fn size(&self) -> usize {
+        let tags: usize = self.tags.iter()
+            .map(|(key, value)| std::mem::size_of::<(String, String)>() + key.len() + value.len())
+            .sum();
+        let member = match &self.metric {
+            MetricType::Set(member) => member.len(),
+            _ => 0
+        };
+
+        std::mem::size_of::<MetricData>()
+            + self.namespace.as_ref().map_or(0, String::len)
+            + self.name.len()
+            + tags
+            + member
+    }
+
+    /// Identifies the series this record belongs to: its path and tags
+    pub fn series(&self) -> Series {
+        (self.path(), self.tags.clone())
+    }
+
+    /// A copy of this record carrying a different value
+    pub fn with_metric(mut self, metric: MetricType) -> MetricData {
+        self.metric = metric;
+        self
+    }
+
+    /// Merges a later record of the same series into this one.
+    ///
+    /// Counters are summed and gauge changes are combined, so a single record describes every
+    /// change made to the series. Other metric types cannot be merged, and the later record is
+    /// handed back.
+    #[allow(clippy::result_large_err)]
+    pub fn merge(&mut self, later: MetricData) -> Result<(), MetricData> {
+        match (&mut self.metric, later.metric) {
+            (MetricType::Counter(count), MetricType::Counter(added)) => {
+                *count = count.saturating_add(added);
+            },
+            (MetricType::Gauge(gauge), MetricType::Gauge(change)) => {
+                *gauge += change;
+            },
+            (_, metric) => return Err(MetricData { metric, ..later })
+        }
+
+        self.occurred = self.occurred.max(later.occurred);
+        Ok(())
+    }
+
+    /// A record of a series derived from this one, named `name.suffix`
+    pub fn derived(&self, suffix: &str, metric: MetricType) -> MetricData {
+        MetricData {
+            namespace: self.namespace.clone(),
+            name: format!("{}.{}", self.name, suffix),
+            occurred: self.occurred,
+            tags: self.tags.clone(),
+            metric
+        }
     }
 }
 
 #[cfg(test)]
 pub fn metric_test_data() -> [MetricData; 4] {
     [
-        MetricData{name: "HelloTimer".to_owned(), occurred: 1,
+        MetricData{name: "HelloTimer".to_owned(), occurred: Duration::from_secs(1), tags: Vec::new(),
             namespace: Option::Some("test".to_owned()), metric: MetricType::Timer(1005) },
-        MetricData{name: "HelloCounter".to_owned(), occurred: 2,
+        MetricData{name: "HelloCounter".to_owned(), occurred: Duration::from_secs(2), tags: Vec::new(),
             namespace: Option::Some("test".to_owned()), metric: MetricType::Counter(12) },
-        MetricData{name: "HelloGauge".to_owned(), occurred: 3,
+        MetricData{name: "HelloGauge".to_owned(), occurred: Duration::from_secs(3), tags: Vec::new(),
             namespace: Option::Some("test".to_owned()), metric: MetricType::Gauge(GaugeOptions::Increase(13))},
-        MetricData{name: "HelloGauge".to_owned(), occurred: 4,
+        MetricData{name: "HelloGauge".to_owned(), occurred: Duration::from_secs(4), tags: Vec::new(),
             namespace: Option::Some("test".to_owned()), metric: MetricType::Gauge(GaugeOptions::Decrease(2)) },
     ]
 }
 
+#[cfg(test)]
+pub fn set_test_data() -> [MetricData; 5] {
+    [
+        MetricData{name: "HelloSet".to_owned(), occurred: Duration::from_secs(1), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Set("alice".to_owned()) },
+        MetricData{name: "HelloCounter".to_owned(), occurred: Duration::from_secs(2), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Counter(12) },
+        MetricData{name: "OtherSet".to_owned(), occurred: Duration::from_secs(6), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Set("7".to_owned()) },
+        MetricData{name: "HelloSet".to_owned(), occurred: Duration::from_secs(5), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Set("bob".to_owned()) },
+        MetricData{name: "HelloSet".to_owned(), occurred: Duration::from_secs(7), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Set("alice".to_owned()) },
+    ]
+}
+
+#[cfg(test)]
+pub fn timer_test_data() -> [MetricData; 5] {
+    [
+        MetricData{name: "HelloTimer".to_owned(), occurred: Duration::from_secs(1), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Timer(30) },
+        MetricData{name: "OtherTimer".to_owned(), occurred: Duration::from_secs(1), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Timer(5) },
+        MetricData{name: "HelloTimer".to_owned(), occurred: Duration::from_secs(2), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Timer(10) },
+        MetricData{name: "HelloTimer".to_owned(), occurred: Duration::from_secs(2), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Timer(40) },
+        MetricData{name: "HelloTimer".to_owned(), occurred: Duration::from_secs(3), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Timer(20) },
+    ]
+}
+
+#[cfg(test)]
+pub fn histogram_test_data() -> [MetricData; 4] {
+    [
+        MetricData{name: "HelloHistogram".to_owned(), occurred: Duration::from_secs(1), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Histogram(8.0) },
+        MetricData{name: "HelloCounter".to_owned(), occurred: Duration::from_secs(2), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Counter(12) },
+        MetricData{name: "HelloHistogram".to_owned(), occurred: Duration::from_secs(5), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Histogram(2.0) },
+        MetricData{name: "HelloHistogram".to_owned(), occurred: Duration::from_secs(3), tags: Vec::new(),
+            namespace: Option::Some("test".to_owned()), metric: MetricType::Histogram(4.0) },
+    ]
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum MetricType {
     Counter(u32),
     Timer(u128),
-    Gauge(GaugeOptions)
+    Gauge(GaugeOptions),
+    Set(String),
+    Histogram(f64),
+    Distribution(f64),
+    /// A value computed client side from other metrics, such as a histogram summary
+    Aggregate(f64)
 }
 
+/// A path which created metrics are placed underneath.
+///
+/// Metrics created from a namespace report to the same collector as the namespace, and
+/// carry the default tags of the namespace.
 #[derive(Clone)]
-pub struct Namespace(pub String);
+pub struct Namespace {
+    path: String,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
+}
 
 impl Namespace {
-    pub fn new(namespace: String) -> Self {
-        Namespace(namespace)
+    pub(crate) fn new(path: String, collector: Arc<dyn Collector>) -> Self {
+        Namespace { path, tags: Vec::new(), collector }
+    }
+
+    /// The dotted path of this namespace
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Add a default tag to every metric created from this namespace and its children
+    pub fn tag(mut self, key: String, value: String) -> Self {
+        self.tags.push((key, value));
+        self
     }
 
     pub fn namespace(&self, namespace: String) -> Self {
-        Namespace::new(format!("{}.{}", self.0, namespace))
+        Namespace {
+            path: format!("{}.{}", self.path, namespace),
+            tags: self.tags.clone(),
+            collector: self.collector.clone()
+        }
     }
 
     pub fn counter(&self, name: String) -> Counter {
-        CounterBuilder::new(name).namespace(Option::Some(self.0.clone())).build()
+        CounterBuilder::new(name, self.collector.clone())
+            .namespace(Option::Some(self.path.clone()))
+            .tags(self.tags.clone())
+            .build()
     }
 
     pub fn timer(&self, name: String) -> Timer {
-        TimerBuilder::new(name).namespace(Option::Some(self.0.clone())).build()
+        TimerBuilder::new(name, self.collector.clone())
+            .namespace(Option::Some(self.path.clone()))
+            .tags(self.tags.clone())
+            .build()
     }
 
     pub fn gauge(&self, name: String) -> Gauge {
-        GaugeBuilder::new(name).namespace(Option::Some(self.0.clone())).build()
+        GaugeBuilder::new(name, self.collector.clone())
+            .namespace(Option::Some(self.path.clone()))
+            .tags(self.tags.clone())
+            .build()
+    }
+
+    pub fn set(&self, name: String) -> Set {
+        SetBuilder::new(name, self.collector.clone())
+            .namespace(Option::Some(self.path.clone()))
+            .tags(self.tags.clone())
+            .build()
+    }
+
+    pub fn histogram(&self, name: String) -> Histogram {
+        HistogramBuilder::new(name, self.collector.clone())
+            .namespace(Option::Some(self.path.clone()))
+            .tags(self.tags.clone())
+            .build()
+    }
+
+    pub fn distribution(&self, name: String) -> Histogram {
+        HistogramBuilder::new(name, self.collector.clone())
+            .namespace(Option::Some(self.path.clone()))
+            .tags(self.tags.clone())
+            .kind(HistogramKind::Distribution)
+            .build()
     }
 }
 
 mod counter;
 mod timer;
 mod gauge;
+mod set;
+mod histogram;
 
 pub use counter::{Counter, CounterBuilder};
 pub use timer::{Timer, TimerBuilder};
-pub use gauge::{Gauge, GaugeBuilder};
-use crate::metric::gauge::GaugeOptions;
+pub use gauge::{Gauge, GaugeBuilder, GaugeOptions};
+pub use set::{Set, SetBuilder};
+pub use histogram::{Histogram, HistogramBuilder, HistogramKind};
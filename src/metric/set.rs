@@ -0,0 +1,135 @@
+use crate::metric::{MetricData, MetricType};
+use std::collections::BTreeSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::collector::Collector;
+use std::sync::Arc;
+
+pub struct SetBuilder {
+    name: String,
+    namespace: Option<String>,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
+}
+
+impl SetBuilder {
+    pub fn new(name: String, collector: Arc<dyn Collector>) -> SetBuilder {
+        SetBuilder{ name, namespace: Option::None, tags: Vec::new(), collector }
+    }
+
+    pub fn namespace(&mut self, namespace: Option<String>) -> &mut Self {
+        self.namespace = namespace;
+        self
+    }
+
+    pub fn tags(&mut self, tags: Vec<(String, String)>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+
+    pub fn build(&self) -> Set {
+        Set {
+            members: BTreeSet::new(),
+            name: self.name.clone(),
+            namespace: self.namespace.clone(),
+            tags: self.tags.clone(),
+            collector: self.collector.clone()
+        }
+    }
+}
+
+/// A metric used to count the unique values seen during a flush interval.
+///
+/// Sets can be created via the `metrical::set` function,
+/// the `MetricsClient::set` method or the `Namespace::set` method.
+///
+/// StatsD counts unique members server side. When publishing to Graphite,
+/// the members of a set are counted client side each time the buffer is flushed.
+///
+/// # Examples
+/// - The number of unique users who logged in
+/// - The number of unique session IDs seen by a service
+pub struct Set {
+    members: BTreeSet<String>,
+    name: String,
+    namespace: Option<String>,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
+}
+
+impl Set {
+    /// Attach a tag to this metric. Tags are published by protocols which support them.
+    pub fn tag(&mut self, key: String, value: String) -> &mut Self {
+        self.tags.push((key, value));
+        self
+    }
+
+    /// Record a member of the set, such as a user name or a numeric ID
+    pub fn insert<M: ToString>(&mut self, member: M) -> &Self {
+        self.members.insert(member.to_string());
+        self
+    }
+
+    /// The records describing each unique member of the set
+    pub fn metrics(&self) -> Vec<MetricData> {
+        let occurred = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        self.members.iter().map(|member| MetricData {
+            namespace: self.namespace.clone(),
+            name: self.name.clone(),
+            occurred,
+            tags: self.tags.clone(),
+            metric: MetricType::Set(member.clone())
+        }).collect()
+    }
+}
+
+impl Drop for Set {
+    fn drop(&mut self) {
+        for metric in self.metrics() {
+            self.collector.send(metric);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::metric::set::SetBuilder;
+    use crate::metric::{MetricData, MetricType};
+    use crate::collector::NullCollector;
+    use std::sync::Arc;
+
+    #[test]
+    pub fn it_should_record_each_member_once() {
+        let mut set = SetBuilder::new("HelloSet".to_owned(), Arc::new(NullCollector))
+            .namespace(Option::Some("test".to_owned()))
+            .build();
+
+        set.insert("alice");
+        set.insert(42);
+        set.insert("alice".to_owned());
+
+        let result = set.metrics();
+        let occurred = result[0].occurred;
+
+        assert_eq!(result, vec![
+            MetricData {
+                namespace: Option::Some("test".to_owned()),
+                name: "HelloSet".to_owned(),
+                occurred,
+                tags: Vec::new(),
+                metric: MetricType::Set("42".to_owned())
+            },
+            MetricData {
+                namespace: Option::Some("test".to_owned()),
+                name: "HelloSet".to_owned(),
+                occurred,
+                tags: Vec::new(),
+                metric: MetricType::Set("alice".to_owned())
+            },
+        ]);
+
+        std::mem::forget(set);
+    }
+}
@@ -1,31 +1,43 @@
 use crate::metric::{MetricGenerator, MetricData, MetricType};
 use std::time::{Instant, SystemTime};
-use crate::shared::collector;
+use crate::collector::Collector;
+use std::sync::Arc;
 
 pub struct TimerBuilder {
     name: String,
-    namespace: Option<String>
+    namespace: Option<String>,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
 }
 
 impl TimerBuilder {
-    pub fn new(name: String) -> TimerBuilder {
+    pub fn new(name: String, collector: Arc<dyn Collector>) -> TimerBuilder {
         TimerBuilder{
             name,
-            namespace: Option::None
+            namespace: Option::None,
+            tags: Vec::new(),
+            collector
         }
     }
 
-    pub fn namespace(&mut self, namespace: Option<String>) -> &Self {
+    pub fn namespace(&mut self, namespace: Option<String>) -> &mut Self {
         self.namespace = namespace;
         self
     }
 
+    pub fn tags(&mut self, tags: Vec<(String, String)>) -> &mut Self {
+        self.tags = tags;
+        self
+    }
+
     pub fn build(&self) -> Timer {
         Timer {
             start: Instant::now(),
             stop: Option::None,
             name: self.name.clone(),
-            namespace: self.namespace.clone()
+            namespace: self.namespace.clone(),
+            tags: self.tags.clone(),
+            collector: self.collector.clone()
         }
     }
 }
@@ -40,12 +52,22 @@ pub struct Timer {
     start: Instant,
     stop: Option<Instant>,
     name: String,
-    namespace: Option<String>
+    namespace: Option<String>,
+    tags: Vec<(String, String)>,
+    collector: Arc<dyn Collector>
+}
+
+impl Timer {
+    /// Attach a tag to this metric. Tags are published by protocols which support them.
+    pub fn tag(&mut self, key: String, value: String) -> &mut Self {
+        self.tags.push((key, value));
+        self
+    }
 }
 
 impl Drop for Timer {
     fn drop(&mut self) {
-        collector().send(self.metric());
+        self.collector.send(self.metric());
     }
 }
 
@@ -60,17 +82,18 @@ impl MetricGenerator for Timer {
             }
         };
 
-        let namespace = self.namespace.clone().take();
+        let namespace = self.namespace.clone();
         let name = self.name.clone();
 
         let occurred = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
-            .map_or(0, |d| d.as_secs());
+            .unwrap_or_default();
 
         MetricData{
             namespace,
             name,
             occurred,
+            tags: self.tags.clone(),
             metric: MetricType::Timer(elapsed)
         }
     }
@@ -80,10 +103,12 @@ impl MetricGenerator for Timer {
 mod test {
     use crate::metric::timer::TimerBuilder;
     use crate::metric::{MetricGenerator, MetricType};
+    use crate::collector::NullCollector;
+    use std::sync::Arc;
 
     #[test]
     pub fn it_should_correctly_give_time() {
-        let timer = TimerBuilder::new("HelloTimer".to_owned())
+        let timer = TimerBuilder::new("HelloTimer".to_owned(), Arc::new(NullCollector))
             .namespace(Option::None)
             .build();
 
@@ -102,7 +127,7 @@ mod test {
             assert!(instant < top_bound);
             assert!(instant > lower_bound);
         } else {
-            assert!(false, "Timer did not return MetricType Timer")
+            panic!("Timer did not return MetricType Timer")
         }
 
         std::mem::forget(timer);
@@ -0,0 +1,504 @@
+use crate::collector::{lock, Collector};
+use crate::metric::{GaugeOptions, MetricData, MetricType};
+use crate::MetricalError;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// The upper bounds of the histogram buckets, matching the Prometheus client defaults
+const DEFAULT_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// The window over which the unique members of a set are counted
+const SET_WINDOW: Duration = Duration::from_secs(60);
+
+type Labels = Vec<(String, String)>;
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram { buckets: vec![0; DEFAULT_BUCKETS.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(DEFAULT_BUCKETS.iter()) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// The unique members of a set, counted over consecutive windows.
+///
+/// Only the members of the current window are kept, so memory is bounded by the members
+/// recorded in one window, and every scraper sees the count of the last complete window.
+struct SetWindow {
+    members: BTreeSet<String>,
+    started: Instant,
+    /// The number of unique members in the window before the current one
+    previous: usize
+}
+
+impl SetWindow {
+    fn new(now: Instant) -> Self {
+        SetWindow { members: BTreeSet::new(), started: now, previous: 0 }
+    }
+
+    fn insert(&mut self, member: String, window: Duration, now: Instant) {
+        let elapsed = now.duration_since(self.started);
+        if elapsed >= window {
+            self.previous = if elapsed >= window * 2 { 0 } else { self.members.len() };
+            self.members.clear();
+            self.started = now;
+        }
+
+        self.members.insert(member);
+    }
+
+    /// The number of unique members in the last complete window
+    fn count(&self, window: Duration, now: Instant) -> usize {
+        let elapsed = now.duration_since(self.started);
+        if elapsed >= window * 2 {
+            0
+        } else if elapsed >= window {
+            self.members.len()
+        } else {
+            self.previous
+        }
+    }
+}
+
+/// Every series of one metric name, along with the Prometheus type of the metric
+enum Family {
+    Counter(BTreeMap<Labels, f64>),
+    Gauge(BTreeMap<Labels, f64>),
+    Histogram(BTreeMap<Labels, Histogram>),
+    Set(BTreeMap<Labels, SetWindow>)
+}
+
+impl Family {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Family::Counter(_) => "counter",
+            Family::Gauge(_) | Family::Set(_) => "gauge",
+            Family::Histogram(_) => "histogram"
+        }
+    }
+}
+
+/// The current value of every series recorded by a `PrometheusCollector`
+struct Registry {
+    families: BTreeMap<String, Family>,
+    set_window: Duration
+}
+
+/// Replaces the characters Prometheus does not allow in metric and label names.
+///
+/// Dots separating the segments of a path become underscores, and names starting with a
+/// digit are prefixed with an underscore.
+fn sanitize_name(name: &str, allow_colon: bool) -> String {
+    let mut sanitized: String = name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || (allow_colon && c == ':') { c } else { '_' })
+        .collect();
+
+    if sanitized.chars().next().is_none_or(|c| c.is_ascii_digit()) {
+        sanitized.insert(0, '_');
+    }
+    sanitized
+}
+
+fn escape_label_value(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render_labels(labels: &[(String, String)], extra: Option<(&str, String)>) -> String {
+    let rendered: Vec<String> = labels.iter()
+        .map(|(key, value)| (key.as_str(), value.clone()))
+        .chain(extra)
+        .map(|(key, value)| format!("{}=\"{}\"", key, escape_label_value(&value)))
+        .collect();
+
+    if rendered.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", rendered.join(","))
+    }
+}
+
+impl Registry {
+    fn new(set_window: Duration) -> Self {
+        Registry { families: BTreeMap::new(), set_window }
+    }
+
+    fn record(&mut self, metric: MetricData) {
+        let mut labels: Labels = metric.tags()
+            .iter()
+            .filter(|(key, _)| !key.is_empty())
+            .map(|(key, value)| (sanitize_name(key, false), value.clone()))
+            .collect::<BTreeMap<_, _>>()
+            .into_iter()
+            .collect();
+        labels.retain(|(key, _)| !key.starts_with("__"));
+
+        let name = sanitize_name(&metric.path(), true);
+
+        match metric.metric() {
+            MetricType::Counter(count) => {
+                if let Family::Counter(series) = self.family(name, || Family::Counter(BTreeMap::new())) {
+                    *series.entry(labels).or_insert(0.0) += f64::from(*count);
+                }
+            },
+            MetricType::Gauge(change) => {
+                if let Family::Gauge(series) = self.family(name, || Family::Gauge(BTreeMap::new())) {
+                    let value = series.entry(labels).or_insert(0.0);
+                    match change {
+                        GaugeOptions::Increase(v) => *value += f64::from(*v),
+                        GaugeOptions::Decrease(v) => *value -= f64::from(*v),
+                        GaugeOptions::Set(v) => *value = f64::from(*v)
+                    }
+                }
+            },
+            MetricType::Aggregate(value) => {
+                if let Family::Gauge(series) = self.family(name, || Family::Gauge(BTreeMap::new())) {
+                    series.insert(labels, *value);
+                }
+            },
+            MetricType::Timer(elapsed) => {
+                let name = format!("{}_seconds", name);
+                if let Family::Histogram(series) = self.family(name, || Family::Histogram(BTreeMap::new())) {
+                    series.entry(labels).or_insert_with(Histogram::new).observe(*elapsed as f64 / 1000.0);
+                }
+            },
+            MetricType::Histogram(value) | MetricType::Distribution(value) => {
+                if let Family::Histogram(series) = self.family(name, || Family::Histogram(BTreeMap::new())) {
+                    series.entry(labels).or_insert_with(Histogram::new).observe(*value);
+                }
+            },
+            MetricType::Set(member) => {
+                let window = self.set_window;
+                if let Family::Set(series) = self.family(name, || Family::Set(BTreeMap::new())) {
+                    let now = Instant::now();
+                    series.entry(labels)
+                        .or_insert_with(|| SetWindow::new(now))
+                        .insert(member.clone(), window, now);
+                }
+            }
+        }
+    }
+
+    /// The family of the given name. A metric recorded with a different type than the first
+    /// metric of the same name is ignored by the caller.
+    fn family<F>(&mut self, name: String, create: F) -> &mut Family where F: FnOnce() -> Family {
+        self.families.entry(name).or_insert_with(create)
+    }
+
+    /// Renders every series in the Prometheus text exposition format.
+    ///
+    /// Sets report the number of unique members recorded in the last complete window, so
+    /// every scraper sees the same count.
+    fn render(&self) -> String {
+        let mut body = String::new();
+        let now = Instant::now();
+
+        for (name, family) in self.families.iter() {
+            body.push_str(&format!("# TYPE {} {}\n", name, family.type_name()));
+
+            match family {
+                Family::Counter(series) | Family::Gauge(series) => {
+                    for (labels, value) in series.iter() {
+                        body.push_str(&format!("{}{} {}\n", name, render_labels(labels, None), value));
+                    }
+                },
+                Family::Histogram(series) => {
+                    for (labels, histogram) in series.iter() {
+                        for (bound, count) in DEFAULT_BUCKETS.iter().zip(histogram.buckets.iter()) {
+                            let le = Some(("le", bound.to_string()));
+                            body.push_str(&format!("{}_bucket{} {}\n", name, render_labels(labels, le), count));
+                        }
+                        let le = Some(("le", "+Inf".to_owned()));
+                        body.push_str(&format!("{}_bucket{} {}\n", name, render_labels(labels, le), histogram.count));
+                        body.push_str(&format!("{}_sum{} {}\n", name, render_labels(labels, None), histogram.sum));
+                        body.push_str(&format!("{}_count{} {}\n", name, render_labels(labels, None), histogram.count));
+                    }
+                },
+                Family::Set(series) => {
+                    for (labels, members) in series.iter() {
+                        let count = members.count(self.set_window, now);
+                        body.push_str(&format!("{}{} {}\n", name, render_labels(labels, None), count));
+                    }
+                }
+            }
+        }
+
+        body
+    }
+}
+
+/// Serves a single scrape. Only `GET /metrics` is answered with the registry.
+fn serve(mut stream: TcpStream, registry: &Mutex<Registry>) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < 8192 {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or("").split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let path = request_line.next().unwrap_or("").split('?').next().unwrap_or("");
+
+    let (status, body) = match (method, path) {
+        ("GET", "/metrics") => ("200 OK", lock(registry).render()),
+        _ => ("404 Not Found", "Not Found\n".to_owned())
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    );
+    stream.write_all(response.as_bytes())?;
+    stream.flush()
+}
+
+/// The background thread answering scrapes
+struct Server {
+    stopped: Arc<AtomicBool>,
+    handle: JoinHandle<()>
+}
+
+impl Server {
+    fn start(listener: TcpListener, registry: Arc<Mutex<Registry>>) -> std::io::Result<Server> {
+        listener.set_nonblocking(true)?;
+        let stopped = Arc::new(AtomicBool::new(false));
+
+        let signal = stopped.clone();
+        let handle = std::thread::Builder::new()
+            .name("metrical-prometheus".to_owned())
+            .spawn(move || {
+                while !signal.load(Ordering::SeqCst) {
+                    match listener.accept() {
+                        // Each scrape is served on its own thread, so a slow scraper does
+                        // not hold back the others. A failed scrape only affects that scraper.
+                        // Without a thread the connection is closed, and the scraper retries.
+                        Ok((stream, _)) => {
+                            let registry = registry.clone();
+                            let _ = std::thread::Builder::new()
+                                .name("metrical-scrape".to_owned())
+                                .spawn(move || { let _ = serve(stream, &registry); });
+                        },
+                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                            std::thread::sleep(Duration::from_millis(50));
+                        },
+                        Err(_) => std::thread::sleep(Duration::from_millis(50))
+                    }
+                }
+            })?;
+
+        Ok(Server { stopped, handle })
+    }
+
+    fn stop(self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        let _ = self.handle.join();
+    }
+}
+
+/// A collector which keeps the current value of every series, and serves them to Prometheus
+/// from an embedded HTTP listener on `/metrics`.
+///
+/// Counters report cumulative totals, gauges their current value, and timers and histograms
+/// are reported as Prometheus histograms. Timers are reported in seconds, under their name
+/// suffixed with `_seconds`. Sets report the number of unique members recorded in the last
+/// complete minute. Dropping the collector stops the listener.
+pub struct PrometheusCollector {
+    registry: Arc<Mutex<Registry>>,
+    server: Option<Server>,
+    namespace: Option<String>
+}
+
+impl PrometheusCollector {
+    /// Serve scrapes from the given listener
+    pub fn new(listener: TcpListener, namespace: Option<String>) -> Result<PrometheusCollector, MetricalError> {
+        let registry = Arc::new(Mutex::new(Registry::new(SET_WINDOW)));
+        let server = Server::start(listener, registry.clone()).map_err(MetricalError::Io)?;
+
+        Ok(PrometheusCollector {
+            registry,
+            server: Some(server),
+            namespace
+        })
+    }
+}
+
+impl Drop for PrometheusCollector {
+    fn drop(&mut self) {
+        if let Some(server) = self.server.take() {
+            server.stop();
+        }
+    }
+}
+
+impl Collector for PrometheusCollector {
+    fn send(&self, data: MetricData) {
+        lock(&self.registry).record(data);
+    }
+
+    /// Metrics are pulled by Prometheus, so there is nothing to flush
+    fn flush(&self) {}
+
+    fn namespace(&self) -> Option<String> {
+        self.namespace.clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::collector::Collector;
+    use crate::metric::{GaugeOptions, MetricData, MetricType};
+    use crate::prometheus::{PrometheusCollector, Registry};
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::time::{Duration, Instant};
+
+    fn scrape(addr: SocketAddr, path: &str) -> std::io::Result<String> {
+        let mut stream = TcpStream::connect(addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path)?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    fn bind() -> std::io::Result<(PrometheusCollector, SocketAddr)> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let addr = listener.local_addr()?;
+        let collector = PrometheusCollector::new(listener, None)
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        Ok((collector, addr))
+    }
+
+    fn metric(name: &str, metric: MetricType) -> MetricData {
+        MetricData::new(Some("test".to_owned()), name.to_owned(), 1, metric)
+    }
+
+    #[test]
+    pub fn it_should_serve_cumulative_counters_and_current_gauges() -> Result<(), Box<dyn std::error::Error>> {
+        let (collector, addr) = bind()?;
+
+        collector.send(metric("HelloCounter", MetricType::Counter(2))
+            .with_tags(vec![("env".to_owned(), "prod".to_owned())]));
+        collector.send(metric("HelloCounter", MetricType::Counter(3))
+            .with_tags(vec![("env".to_owned(), "prod".to_owned())]));
+        collector.send(metric("HelloGauge", MetricType::Gauge(GaugeOptions::Set(10))));
+        collector.send(metric("HelloGauge", MetricType::Gauge(GaugeOptions::Decrease(4))));
+
+        let response = scrape(addr, "/metrics")?;
+
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(response.ends_with("\r\n\r\n\
+            # TYPE test_HelloCounter counter\n\
+            test_HelloCounter{env=\"prod\"} 5\n\
+            # TYPE test_HelloGauge gauge\n\
+            test_HelloGauge 6\n"));
+
+        let response = scrape(addr, "/metrics")?;
+        assert!(response.contains("test_HelloCounter{env=\"prod\"} 5\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_count_set_members_over_each_window() {
+        let mut registry = Registry::new(Duration::from_millis(300));
+        for member in ["alice", "bob", "alice"] {
+            registry.record(metric("HelloSet", MetricType::Set(member.to_owned())));
+        }
+        assert!(registry.render().contains("test_HelloSet 0\n"));
+
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(registry.render().contains("test_HelloSet 2\n"));
+
+        // Members of the previous window are forgotten once a new one starts
+        registry.record(metric("HelloSet", MetricType::Set("carol".to_owned())));
+        assert!(registry.render().contains("test_HelloSet 2\n"));
+        std::thread::sleep(Duration::from_millis(400));
+        assert!(registry.render().contains("test_HelloSet 1\n"));
+
+        std::thread::sleep(Duration::from_millis(300));
+        assert!(registry.render().contains("test_HelloSet 0\n"));
+    }
+
+    #[test]
+    pub fn it_should_serve_timers_as_histograms() -> Result<(), Box<dyn std::error::Error>> {
+        let (collector, addr) = bind()?;
+
+        collector.send(metric("HelloTimer", MetricType::Timer(20)));
+        collector.send(metric("HelloTimer", MetricType::Timer(300)));
+
+        let response = scrape(addr, "/metrics")?;
+
+        assert!(response.contains("# TYPE test_HelloTimer_seconds histogram\n"));
+        assert!(response.contains("test_HelloTimer_seconds_bucket{le=\"0.01\"} 0\n"));
+        assert!(response.contains("test_HelloTimer_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(response.contains("test_HelloTimer_seconds_bucket{le=\"0.5\"} 2\n"));
+        assert!(response.contains("test_HelloTimer_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(response.contains("test_HelloTimer_seconds_sum 0.32\n"));
+        assert!(response.contains("test_HelloTimer_seconds_count 2\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_not_wait_for_a_slow_scraper() -> Result<(), Box<dyn std::error::Error>> {
+        let (_collector, addr) = bind()?;
+
+        // A scraper which never sends its request
+        let _slow = TcpStream::connect(addr)?;
+        std::thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        assert!(scrape(addr, "/metrics")?.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(started.elapsed() < Duration::from_secs(2));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_answer_other_paths_with_not_found() -> Result<(), Box<dyn std::error::Error>> {
+        let (_collector, addr) = bind()?;
+
+        let response = scrape(addr, "/")?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_stop_listening_when_dropped() -> Result<(), Box<dyn std::error::Error>> {
+        let (collector, addr) = bind()?;
+        drop(collector);
+
+        assert!(TcpStream::connect(addr).is_err());
+
+        Ok(())
+    }
+}
@@ -1,45 +1,241 @@
-use crate::metric::{MetricData, MetricType};
+use crate::metric::{MetricData, MetricType, GaugeOptions, Series};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 
 #[cfg(feature = "pickle")]
 use serde_pickle::ser;
 
 pub enum Protocol {
     StatsD,
-    Graphite(Compression)
+    /// StatsD extended with Datadog tags (`|#key:value`)
+    DogStatsD,
+    Graphite(Compression),
+    /// The InfluxDB line protocol, with nanosecond timestamps
+    InfluxLine,
+    /// The OpenTSDB telnet `put` protocol. OpenTSDB only accepts this protocol over TCP.
+    ///
+    /// OpenTSDB stores every value as is, so relative gauge changes would be taken for
+    /// absolute values. They are skipped; only gauges set to a value are published.
+    OpenTSDB(OpenTSDBMapping)
+}
+
+/// How dotted metric paths are mapped onto OpenTSDB metric names and tags.
+///
+/// OpenTSDB requires every data point to carry at least one tag. The leading segments of
+/// each path can be turned into tags with `path_tag`, and tags can be added to every data
+/// point with `default_tag`. Data points which end up without any tag are tagged
+/// `source=metrical`.
+///
+/// # Example
+/// With the path tags `env` and `host`, the path `prod.web01.api.requests` is published as
+/// the metric `api.requests` with the tags `env=prod` and `host=web01`.
+#[derive(Clone, Default)]
+pub struct OpenTSDBMapping {
+    path_tags: Vec<String>,
+    default_tags: Vec<(String, String)>
+}
+
+impl OpenTSDBMapping {
+    pub fn new() -> Self {
+        OpenTSDBMapping::default()
+    }
+
+    /// Take a tag with the given name from the next leading segment of each path
+    pub fn path_tag(mut self, name: String) -> Self {
+        self.path_tags.push(name);
+        self
+    }
+
+    /// Add a tag to every data point
+    pub fn default_tag(mut self, key: String, value: String) -> Self {
+        self.default_tags.push((key, value));
+        self
+    }
+
+    /// Replaces characters OpenTSDB does not allow in metric names and tags.
+    ///
+    /// OpenTSDB allows letters, digits, `-`, `_`, `.` and `/`.
+    pub fn sanitize(part: &str) -> String {
+        part.chars()
+            .map(|c| if c.is_alphanumeric() || "-_./".contains(c) { c } else { '_' })
+            .collect()
+    }
+
+    /// The metric name and tags of a record.
+    ///
+    /// Path tags are only taken while at least one segment remains for the metric name.
+    /// Tags recorded on the metric replace default and path tags with the same name.
+    pub fn map(&self, metric: &MetricData) -> (String, BTreeMap<String, String>) {
+        let path = metric.path();
+        let mut segments: Vec<&str> = path.split('.').collect();
+
+        let taken = self.path_tags.len().min(segments.len() - 1);
+        let leading: Vec<&str> = segments.drain(..taken).collect();
+
+        let mut tags = BTreeMap::new();
+        let tag_values = self.default_tags.iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .chain(self.path_tags.iter().map(String::as_str).zip(leading))
+            .chain(metric.tags().iter().map(|(key, value)| (key.as_str(), value.as_str())));
+
+        for (key, value) in tag_values {
+            if !key.is_empty() && !value.is_empty() {
+                tags.insert(OpenTSDBMapping::sanitize(key), OpenTSDBMapping::sanitize(value));
+            }
+        }
+
+        if tags.is_empty() {
+            tags.insert("source".to_owned(), "metrical".to_owned());
+        }
+
+        (OpenTSDBMapping::sanitize(&segments.join(".")), tags)
+    }
 }
 
 type PickleRecord = (String, (String, String));
 
 impl Protocol {
-    pub fn serialized_statsd_record(metric: MetricData) -> Vec<u8> {
+    /// A StatsD record, without tags or a trailing newline
+    pub fn statsd_record(metric: &MetricData) -> String {
         let path = metric.path();
         match metric.metric() {
-            MetricType::Counter(count) => format!("{}:{}|c\n", path, count).into_bytes(),
-            MetricType::Timer(elapsed) => format!("{}:{}|ms\n", path, elapsed).into_bytes(),
-            MetricType::Gauge(gauge) => format!("{}:{}|g\n", path, gauge).into_bytes()
+            MetricType::Counter(count) => format!("{}:{}|c", path, count),
+            MetricType::Timer(elapsed) => format!("{}:{}|ms", path, elapsed),
+            MetricType::Gauge(gauge) => format!("{}:{}|g", path, gauge),
+            MetricType::Set(member) => format!("{}:{}|s", path, Protocol::statsd_set_member(member)),
+            MetricType::Histogram(value) => format!("{}:{}|h", path, value),
+            MetricType::Distribution(value) => format!("{}:{}|d", path, value),
+            MetricType::Aggregate(value) => format!("{}:{}|g", path, value)
         }
     }
 
+    /// A set member as written in a StatsD record. Characters which would break the record
+    /// framing are replaced with underscores.
+    pub fn statsd_set_member(member: &str) -> String {
+        member.chars()
+            .map(|c| if c == '|' || c == ':' || c.is_control() { '_' } else { c })
+            .collect()
+    }
+
+    pub fn serialized_statsd_record(metric: MetricData) -> Vec<u8> {
+        format!("{}\n", Protocol::statsd_record(&metric)).into_bytes()
+    }
+
+    /// A DogStatsD tag. Characters which would break the record framing are replaced
+    /// with underscores, and tags without a value are sent as a bare key.
+    pub fn dogstatsd_tag(key: &str, value: &str) -> String {
+        let sanitize = |part: &str, reserved: &[char]| -> String {
+            part.chars()
+                .map(|c| if reserved.contains(&c) || c.is_control() { '_' } else { c })
+                .collect()
+        };
+
+        let key = sanitize(key, &[',', '|', '#', ':']);
+        if value.is_empty() {
+            key
+        } else {
+            format!("{}:{}", key, sanitize(value, &[',', '|', '#']))
+        }
+    }
+
+    pub fn serialized_dogstatsd_record(metric: MetricData) -> Vec<u8> {
+        let record = Protocol::statsd_record(&metric);
+        if metric.tags().is_empty() {
+            return format!("{}\n", record).into_bytes();
+        }
+
+        let tags: Vec<String> = metric.tags()
+            .iter()
+            .map(|(key, value)| Protocol::dogstatsd_tag(key, value))
+            .collect();
+
+        format!("{}|#{}\n", record, tags.join(",")).into_bytes()
+    }
+
+    pub fn serialize_dogstatsd<I>(metrics: I) -> Vec<u8> where
+    I: IntoIterator<Item=MetricData>
+    {
+        let mut data: Vec<u8> = metrics
+            .into_iter()
+            .flat_map(Protocol::serialized_dogstatsd_record)
+            .collect();
+        data.pop();
+        data
+    }
+
     pub fn serialize_statsd<I>(metrics: I) -> Vec<u8> where
     I: IntoIterator<Item=MetricData>
     {
         let mut data: Vec<u8> = metrics
             .into_iter()
-            .map(Protocol::serialized_statsd_record)
-            .flatten()
+            .flat_map(Protocol::serialized_statsd_record)
             .collect();
         data.pop();
         data
     }
 
+    /// The value of a record as published to Graphite.
+    ///
+    /// Sets and histograms are aggregated before they are published to Graphite, so a raw
+    /// set member counts as one, and a raw histogram value is published as is.
+    pub fn graphite_value(metric: &MetricType) -> String {
+        match metric {
+            MetricType::Counter(count) => count.to_string(),
+            MetricType::Timer(elapsed) => elapsed.to_string(),
+            MetricType::Gauge(gauge) => gauge.to_string(),
+            MetricType::Set(_) => 1.to_string(),
+            MetricType::Histogram(value)
+            | MetricType::Distribution(value)
+            | MetricType::Aggregate(value) => value.to_string()
+        }
+    }
+
+    /// Replaces the characters Graphite does not allow in a tag name or value.
+    ///
+    /// Tag names may not contain `;!^=`, and tag values may not contain `;` or start with `~`.
+    /// Whitespace would split the plaintext record, so it is replaced as well. Returns `None`
+    /// if the name or value is empty.
+    pub fn graphite_tag(key: &str, value: &str) -> Option<(String, String)> {
+        if key.is_empty() || value.is_empty() {
+            return None;
+        }
+
+        let key = key.chars()
+            .map(|c| if ";!^=".contains(c) || c.is_whitespace() { '_' } else { c })
+            .collect();
+
+        let value = value.chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if c == ';' || c.is_whitespace() || (i == 0 && c == '~') { '_' } else { c }
+            })
+            .collect();
+
+        Some((key, value))
+    }
+
+    /// The path of a record in Graphite's tagged series format, `path;key=value;key2=value2`.
+    ///
+    /// Tags are sorted by name, as Graphite does, and a later tag replaces an earlier tag
+    /// with the same name. Records without tags use their plain path.
+    pub fn graphite_path(metric: &MetricData) -> String {
+        let tags: BTreeMap<String, String> = metric.tags()
+            .iter()
+            .filter_map(|(key, value)| Protocol::graphite_tag(key, value))
+            .collect();
+
+        tags.into_iter().fold(metric.path(), |path, (key, value)| {
+            format!("{};{}={}", path, key, value)
+        })
+    }
+
+    /// A Graphite plaintext record. Carbon reads one record per line, so every record ends
+    /// with a newline.
     pub fn serialized_graphite_record(metric: MetricData) -> Vec<u8> {
         let occurred = metric.occurred();
-        let path = metric.path();
-        match metric.metric() {
-            MetricType::Counter(count) => format!("{} {} {}", path, count, occurred).into_bytes(),
-            MetricType::Timer(elapsed) => format!("{} {} {}", path, elapsed, occurred).into_bytes(),
-            MetricType::Gauge(gauge) => format!("{} {} {}", path, gauge, occurred).into_bytes()
-        }
+        let path = Protocol::graphite_path(&metric);
+        let value = Protocol::graphite_value(metric.metric());
+
+        format!("{} {} {}\n", path, value, occurred).into_bytes()
     }
 
     pub fn serialize_graphite_uncompressed<I>(metrics: I) -> Vec<u8> where
@@ -53,14 +249,105 @@ impl Protocol {
 
     pub fn pickle_tuple(metric: MetricData) -> PickleRecord {
         let occurred = metric.occurred().to_string();
-        let path = metric.path();
-        let value = match metric.metric() {
-            MetricType::Counter(count) => count.to_string(),
-            MetricType::Timer(elapsed) => elapsed.to_string(),
-            MetricType::Gauge(gauge) => gauge.to_string()
-        };
+        let path = Protocol::graphite_path(&metric);
+        let value = Protocol::graphite_value(metric.metric());
+
+        (path, (value, occurred))
+    }
 
-        return (path, (value, occurred))
+    /// Escapes the characters the line protocol reserves. Measurements reserve `,` and
+    /// spaces, while tag keys, tag values and field keys also reserve `=`.
+    pub fn influx_escape(part: &str, reserved: &[char]) -> String {
+        let mut escaped = String::with_capacity(part.len());
+        for c in part.chars() {
+            if c.is_control() {
+                escaped.push('_');
+                continue;
+            }
+            if c == '\\' || reserved.contains(&c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    /// The field set of a record. Integers carry the `i` suffix, and relative gauge changes
+    /// are published as a signed `delta` field since InfluxDB has no relative gauges. Sets are
+    /// counted before they are published, so a raw set member counts as one.
+    pub fn influx_field(metric: &MetricType) -> String {
+        match metric {
+            MetricType::Counter(count) => format!("count={}i", count),
+            MetricType::Timer(elapsed) => format!("elapsed_ms={}i", elapsed),
+            MetricType::Gauge(GaugeOptions::Set(value)) => format!("value={}i", value),
+            MetricType::Gauge(GaugeOptions::Increase(change)) => format!("delta={}i", i64::from(*change)),
+            MetricType::Gauge(GaugeOptions::Decrease(change)) => format!("delta={}i", -i64::from(*change)),
+            MetricType::Set(_) => "value=1i".to_owned(),
+            MetricType::Histogram(value)
+            | MetricType::Distribution(value)
+            | MetricType::Aggregate(value) => format!("value={}", value)
+        }
+    }
+
+    /// A line protocol record: `measurement,tag=value field=value timestamp`.
+    ///
+    /// The measurement is the dotted path of the record. Tags are sorted by key, as InfluxDB
+    /// recommends, and tags with an empty key or value are dropped.
+    pub fn serialized_influx_record(metric: MetricData) -> Vec<u8> {
+        let mut record = Protocol::influx_escape(&metric.path(), &[',', ' ']);
+
+        let tags: BTreeMap<&str, &str> = metric.tags()
+            .iter()
+            .filter(|(key, value)| !key.is_empty() && !value.is_empty())
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+            .collect();
+
+        for (key, value) in tags {
+            record.push(',');
+            record.push_str(&Protocol::influx_escape(key, &[',', '=', ' ']));
+            record.push('=');
+            record.push_str(&Protocol::influx_escape(value, &[',', '=', ' ']));
+        }
+
+        format!(
+            "{} {} {}\n", record, Protocol::influx_field(metric.metric()), metric.occurred_nanos()
+        ).into_bytes()
+    }
+
+    /// Every line, including the last, ends with a newline so records sent over a stream
+    /// are never joined together.
+    pub fn serialize_influx_line<I>(metrics: I) -> Vec<u8> where
+        I: IntoIterator<Item=MetricData>
+    {
+        metrics
+            .into_iter()
+            .flat_map(Protocol::serialized_influx_record)
+            .collect()
+    }
+
+    /// An OpenTSDB `put` command, with a millisecond timestamp
+    pub fn serialized_opentsdb_record(mapping: &OpenTSDBMapping, metric: MetricData) -> Vec<u8> {
+        let (name, tags) = mapping.map(&metric);
+        let tags: Vec<String> = tags.into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+
+        format!(
+            "put {} {} {} {}\n",
+            name,
+            metric.occurred_nanos() / 1_000_000,
+            Protocol::graphite_value(metric.metric()),
+            tags.join(" ")
+        ).into_bytes()
+    }
+
+    pub fn serialize_opentsdb<I>(mapping: &OpenTSDBMapping, metrics: I) -> Vec<u8> where
+        I: IntoIterator<Item=MetricData>
+    {
+        metrics
+            .into_iter()
+            .flat_map(|metric| Protocol::serialized_opentsdb_record(mapping, metric))
+            .collect()
     }
 
     #[cfg(feature = "pickle")]
@@ -73,12 +360,12 @@ impl Protocol {
             Protocol::pickle_tuple
         ).collect();
 
-        let data = ser::to_vec(&data, true).unwrap_or(Vec::new());
+        let data = ser::to_vec(&data, true).unwrap_or_default();
 
         let size = data.len() as u32;
         let mut buf = [0; 4];
         BigEndian::write_u32(&mut buf, size);
-        buf.to_vec().into_iter().chain(data.into_iter()).collect::<Vec<_>>()
+        buf.iter().copied().chain(data).collect::<Vec<_>>()
     }
 
     pub fn serialize_data<I>(&self, metrics: I) -> Vec<u8>
@@ -87,40 +374,233 @@ impl Protocol {
         match self {
             Protocol::StatsD => Protocol::serialize_statsd(metrics),
 
+            Protocol::DogStatsD => Protocol::serialize_dogstatsd(metrics),
+
             #[cfg(feature = "pickle")]
             Protocol::Graphite(Compression::Pickled) => Protocol::serialize_graphite_pickled(metrics),
 
             Protocol::Graphite(Compression::Uncompressed) =>
-                Protocol::serialize_graphite_uncompressed(metrics)
+                Protocol::serialize_graphite_uncompressed(metrics),
+
+            Protocol::InfluxLine => Protocol::serialize_influx_line(metrics),
+
+            Protocol::OpenTSDB(mapping) => Protocol::serialize_opentsdb(mapping, metrics)
         }
     }
 }
 
 impl Protocol {
-    pub fn serialize<I>(&self, data: I) -> Vec<Vec<u8>> where I: IntoIterator<Item=MetricData> {
-        let mut data = data.into_iter().peekable();
+    /// Replaces the members of each set with a single record counting its unique members.
+    ///
+    /// Graphite, InfluxDB and OpenTSDB have no notion of sets, so sets are counted client side
+    /// once per flush. The count is placed where the first member of the set appeared, and
+    /// carries the time the last member was recorded.
+    pub fn count_set_members<I>(metrics: I) -> Vec<MetricData> where
+        I: IntoIterator<Item=MetricData>
+    {
+        let mut counted: Vec<Option<MetricData>> = Vec::new();
+        let mut sets: HashMap<Series, (usize, BTreeSet<String>)> = HashMap::new();
 
-        let num_in_packet = match self {
-            Protocol::StatsD => 14,
-            Protocol::Graphite(Compression::Uncompressed) => 1,
-            #[cfg(feature = "pickle")]
-            Protocol::Graphite(Compression::Pickled) => 14
+        for metric in metrics {
+            let member = match metric.metric() {
+                MetricType::Set(member) => member.clone(),
+                _ => {
+                    counted.push(Some(metric));
+                    continue;
+                }
+            };
+
+            let (index, members) = sets.entry(metric.series()).or_insert_with(|| {
+                counted.push(None);
+                (counted.len() - 1, BTreeSet::new())
+            });
+
+            members.insert(member);
+            let count = members.len() as u32;
+            counted[*index] = Some(metric.with_metric(MetricType::Gauge(GaugeOptions::Set(count))));
+        }
+
+        counted.into_iter().flatten().collect()
+    }
+
+    /// The plaintext form of a single record, framed as it is sent
+    pub fn serialized_record(&self, metric: MetricData) -> Vec<u8> {
+        match self {
+            Protocol::StatsD => Protocol::serialized_statsd_record(metric),
+            Protocol::DogStatsD => Protocol::serialized_dogstatsd_record(metric),
+            Protocol::Graphite(_) => Protocol::serialized_graphite_record(metric),
+            Protocol::InfluxLine => Protocol::serialized_influx_record(metric),
+            Protocol::OpenTSDB(mapping) => Protocol::serialized_opentsdb_record(mapping, metric)
+        }
+    }
+
+    /// Groups sized items so the sizes within each group add up to at most `max_payload`,
+    /// keeping their order. An item larger than `max_payload` is placed in a group of its own.
+    fn pack<T>(items: Vec<(T, usize)>, max_payload: usize) -> Vec<Vec<T>> {
+        let mut groups = Vec::new();
+        let mut group = Vec::new();
+        let mut group_size = 0;
+
+        for (item, size) in items {
+            if !group.is_empty() && group_size + size > max_payload {
+                groups.push(std::mem::take(&mut group));
+                group_size = 0;
+            }
+
+            group.push(item);
+            group_size += size;
+        }
+
+        if !group.is_empty() {
+            groups.push(group);
+        }
+        groups
+    }
+
+    /// Pickles each group of records, splitting any group whose payload turns out larger
+    /// than estimated.
+    #[cfg(feature = "pickle")]
+    fn serialize_pickled_packets(metrics: Vec<MetricData>, max_payload: usize) -> Vec<Vec<u8>> {
+        // The length header, protocol, list and stop opcodes surrounding the records
+        const PICKLE_OVERHEAD: usize = 10;
+
+        let records: Vec<(MetricData, usize)> = metrics.into_iter().map(|metric| {
+            let size = ser::to_vec(&Protocol::pickle_tuple(metric.clone()), true)
+                .map_or(0, |record| record.len().saturating_sub(3));
+            (metric, size)
+        }).collect();
+
+        let mut groups = Protocol::pack(records, max_payload.saturating_sub(PICKLE_OVERHEAD));
+        groups.reverse();
+
+        let mut packets = Vec::new();
+        while let Some(mut group) = groups.pop() {
+            let packet = Protocol::serialize_graphite_pickled(group.clone());
+            if packet.len() > max_payload && group.len() > 1 {
+                let second = group.split_off(group.len() / 2);
+                groups.push(second);
+                groups.push(group);
+            } else {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
+
+    /// Serializes the metrics into packets of at most `max_payload` bytes.
+    ///
+    /// Records are never split across packets. A record larger than `max_payload` by itself
+    /// is sent alone in its own packet.
+    pub fn serialize<I>(&self, data: I, max_payload: usize) -> Vec<Vec<u8>> where I: IntoIterator<Item=MetricData> {
+        let data: Vec<MetricData> = match self {
+            Protocol::StatsD | Protocol::DogStatsD => data.into_iter().collect(),
+            Protocol::Graphite(_) | Protocol::InfluxLine => Protocol::count_set_members(data),
+            Protocol::OpenTSDB(_) => Protocol::count_set_members(data.into_iter().filter(|metric| {
+                !matches!(metric.metric(), MetricType::Gauge(GaugeOptions::Increase(_) | GaugeOptions::Decrease(_)))
+            }))
         };
 
-        let mut packet_bodies = Vec::new();
-        while data.peek().is_some() {
-            let chunk: Vec<_> = data.by_ref().take(num_in_packet).collect();
+        #[cfg(feature = "pickle")]
+        if let Protocol::Graphite(Compression::Pickled) = self {
+            return Protocol::serialize_pickled_packets(data, max_payload);
+        }
+
+        let records: Vec<(Vec<u8>, usize)> = data.into_iter().map(|metric| {
+            let record = self.serialized_record(metric);
+            let size = record.len();
+            (record, size)
+        }).collect();
+
+        Protocol::pack(records, max_payload).into_iter().map(|records| {
+            let mut packet = records.concat();
+            // StatsD datagrams separate their records with newlines, without a trailing one
+            if let Protocol::StatsD | Protocol::DogStatsD = self {
+                packet.pop();
+            }
+            packet
+        }).collect()
+    }
 
-            packet_bodies.push(self.serialize_data(chunk));
+    /// Frames a serialized packet for the network protocol it is sent over.
+    ///
+    /// StatsD records sent over a stream keep their trailing newline, so consecutive writes
+    /// do not run together. DogStatsD over a Unix domain stream socket is also prefixed with
+    /// its length as a little endian `u32`, as the Datadog agent expects.
+    pub fn frame(&self, mut packet: Vec<u8>, network_protocol: NetworkProtocol) -> Vec<u8> {
+        if !matches!(self, Protocol::StatsD | Protocol::DogStatsD) || network_protocol.is_datagram() {
+            return packet;
         }
+        packet.push(b'\n');
+
+        #[cfg(unix)]
+        if let (Protocol::DogStatsD, NetworkProtocol::UnixStream) = (self, network_protocol) {
+            use byteorder::{ByteOrder, LittleEndian};
 
-        packet_bodies
+            let mut framed = vec![0; 4];
+            LittleEndian::write_u32(&mut framed, packet.len() as u32);
+            framed.extend(packet);
+            return framed;
+        }
+
+        packet
     }
 }
 
+/// The default maximum payload of a UDP packet, which fits an Ethernet MTU once IP and UDP
+/// headers are added
+pub const DEFAULT_UDP_PAYLOAD_SIZE: usize = 1432;
+
+/// The default maximum size of each write to a TCP stream
+pub const DEFAULT_TCP_PAYLOAD_SIZE: usize = 64 * 1024;
+
+/// The default maximum payload of a Unix domain socket datagram, as recommended for the
+/// Datadog agent
+pub const DEFAULT_UNIX_DATAGRAM_PAYLOAD_SIZE: usize = 8192;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy)]
 pub enum NetworkProtocol {
     UDP,
-    TCP
+    TCP,
+    /// A TLS encrypted TCP stream, configured by `ConfigurationBuilder::tls`
+    #[cfg(feature = "tls")]
+    TLS,
+    /// Datagrams sent to a Unix domain socket, such as the one of the Datadog agent
+    #[cfg(unix)]
+    UnixDatagram,
+    /// A stream over a Unix domain socket
+    #[cfg(unix)]
+    UnixStream
+}
+
+impl NetworkProtocol {
+    /// Whether payloads are sent as datagrams rather than over a stream
+    pub fn is_datagram(&self) -> bool {
+        match self {
+            NetworkProtocol::UDP => true,
+            NetworkProtocol::TCP => false,
+            #[cfg(feature = "tls")]
+            NetworkProtocol::TLS => false,
+            #[cfg(unix)]
+            NetworkProtocol::UnixDatagram => true,
+            #[cfg(unix)]
+            NetworkProtocol::UnixStream => false
+        }
+    }
+
+    /// The maximum payload size used when none is configured
+    pub fn default_payload_size(&self) -> usize {
+        match self {
+            NetworkProtocol::UDP => DEFAULT_UDP_PAYLOAD_SIZE,
+            NetworkProtocol::TCP => DEFAULT_TCP_PAYLOAD_SIZE,
+            #[cfg(feature = "tls")]
+            NetworkProtocol::TLS => DEFAULT_TCP_PAYLOAD_SIZE,
+            #[cfg(unix)]
+            NetworkProtocol::UnixDatagram => DEFAULT_UNIX_DATAGRAM_PAYLOAD_SIZE,
+            #[cfg(unix)]
+            NetworkProtocol::UnixStream => DEFAULT_TCP_PAYLOAD_SIZE
+        }
+    }
 }
 
 pub enum Compression {
@@ -133,28 +613,70 @@ pub enum Compression {
 #[cfg(test)]
 mod test {
     mod uncompressed {
-        use crate::metric::{metric_test_data};
-        use crate::protocol::{Protocol, Compression};
+        use crate::metric::{metric_test_data, set_test_data, MetricData, MetricType};
+        use crate::protocol::{Protocol, Compression, DEFAULT_UDP_PAYLOAD_SIZE};
 
         #[test]
-        pub fn it_should_handle_one_metric_per_entry() {
+        pub fn it_should_batch_newline_terminated_records() {
             let data = metric_test_data().to_vec();
             let protocol = Protocol::Graphite(Compression::Uncompressed);
-            let result: Vec<Vec<u8>> = protocol.serialize(data);
+            let result: Vec<Vec<u8>> = protocol.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
 
-            assert_eq!(result.len(), 4);
+            assert_eq!(result, vec![
+                b"test.HelloTimer 1005 1\n\
+                test.HelloCounter 12 2\n\
+                test.HelloGauge +13 3\n\
+                test.HelloGauge -2 4\n".to_vec()
+            ]);
+        }
+
+        #[test]
+        pub fn it_should_end_every_packet_with_a_newline() {
+            let data = metric_test_data().to_vec();
+            let protocol = Protocol::Graphite(Compression::Uncompressed);
+            let result: Vec<Vec<u8>> = protocol.serialize(data, 50);
 
-            let payload1 = result[0].clone();
-            assert_eq!(payload1, b"test.HelloTimer 1005 1".to_vec());
+            assert_eq!(result, vec![
+                b"test.HelloTimer 1005 1\ntest.HelloCounter 12 2\n".to_vec(),
+                b"test.HelloGauge +13 3\ntest.HelloGauge -2 4\n".to_vec(),
+            ]);
+        }
 
-            let payload2 = result[1].clone();
-            assert_eq!(payload2, b"test.HelloCounter 12 2".to_vec());
+        #[test]
+        pub fn it_should_append_sorted_tags_to_the_path() {
+            let data = vec![
+                MetricData::new(Some("test".to_owned()), "HelloCounter".to_owned(), 2, MetricType::Counter(12))
+                    .with_tags(vec![
+                        ("region".to_owned(), "us-east".to_owned()),
+                        ("env".to_owned(), "staging".to_owned()),
+                        ("env".to_owned(), "prod".to_owned()),
+                    ])
+            ];
 
-            let payload3 = result[2].clone();
-            assert_eq!(payload3, b"test.HelloGauge +13 3".to_vec());
+            let result = Protocol::Graphite(Compression::Uncompressed).serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
 
-            let payload4 = result[3].clone();
-            assert_eq!(payload4, b"test.HelloGauge -2 4".to_vec());
+            assert_eq!(result, vec![b"test.HelloCounter;env=prod;region=us-east 12 2\n".to_vec()]);
+        }
+
+        #[test]
+        pub fn it_should_escape_tags() {
+            assert_eq!(
+                Protocol::graphite_tag("a;b!c^d=e f", "~g;h i~"),
+                Some(("a_b_c_d_e_f".to_owned(), "_g_h_i~".to_owned()))
+            );
+            assert_eq!(Protocol::graphite_tag("", "value"), None);
+            assert_eq!(Protocol::graphite_tag("key", ""), None);
+        }
+
+        #[test]
+        pub fn it_should_count_unique_set_members() {
+            let data = set_test_data().to_vec();
+            let protocol = Protocol::Graphite(Compression::Uncompressed);
+            let result: Vec<Vec<u8>> = protocol.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(result, vec![
+                b"test.HelloSet 2 7\ntest.HelloCounter 12 2\ntest.OtherSet 1 6\n".to_vec()
+            ]);
         }
     }
 
@@ -163,12 +685,12 @@ mod test {
         #[cfg(feature = "pickle")]
         pub fn it_should_pickle_properly() -> Result<(), Box<dyn std::error::Error>> {
             use crate::metric::metric_test_data;
-            use crate::protocol::{Protocol, Compression};
+            use crate::protocol::{Protocol, Compression, DEFAULT_UDP_PAYLOAD_SIZE};
             let data = metric_test_data().to_vec();
 
             let protocol = Protocol::Graphite(Compression::Pickled);
 
-            let pickle_data: Vec<Vec<u8>> = protocol.serialize(data);
+            let pickle_data: Vec<Vec<u8>> = protocol.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
             let data_point = &pickle_data[0][4..];
 
             assert_eq!(pickle_data.len(), 1);
@@ -176,21 +698,378 @@ mod test {
 
             Ok(())
         }
+
+        #[test]
+        #[cfg(feature = "pickle")]
+        pub fn it_should_pickle_tagged_paths() -> Result<(), Box<dyn std::error::Error>> {
+            use crate::metric::{MetricData, MetricType};
+            use crate::protocol::{Protocol, Compression, DEFAULT_UDP_PAYLOAD_SIZE};
+
+            let data = vec![
+                MetricData::new(None, "HelloCounter".to_owned(), 2, MetricType::Counter(12))
+                    .with_tags(vec![("env".to_owned(), "prod".to_owned())])
+            ];
+
+            let pickle_data = Protocol::Graphite(Compression::Pickled).serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+            let records: Vec<(String, (String, String))> =
+                serde_pickle::from_slice(&pickle_data[0][4..])?;
+
+            assert_eq!(records, vec![
+                ("HelloCounter;env=prod".to_owned(), ("12".to_owned(), "2".to_owned()))
+            ]);
+
+            Ok(())
+        }
+    }
+
+    mod dogstatsd {
+        use crate::metric::{metric_test_data, MetricData, MetricType};
+        use crate::protocol::{Protocol, DEFAULT_UDP_PAYLOAD_SIZE};
+
+        #[test]
+        pub fn it_should_send_tags() {
+            let data: Vec<MetricData> = metric_test_data().iter().cloned().enumerate().map(|(i, metric)| {
+                match i {
+                    0 => metric.with_tags(vec![("env".to_owned(), "prod".to_owned())]),
+                    1 => metric.with_tags(vec![
+                        ("env".to_owned(), "prod".to_owned()),
+                        ("region".to_owned(), "us-east".to_owned())
+                    ]),
+                    _ => metric
+                }
+            }).collect();
+
+            let data = Protocol::DogStatsD.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data.len(), 1);
+            assert_eq!(
+                String::from_utf8_lossy(&data[0]),
+                "test.HelloTimer:1005|ms|#env:prod\ntest.HelloCounter:12|c|#env:prod,region:us-east\ntest.HelloGauge:+13|g\ntest.HelloGauge:-2|g"
+            );
+        }
+
+        #[test]
+        pub fn it_should_sanitize_tags() {
+            assert_eq!(Protocol::dogstatsd_tag("role", ""), "role");
+            assert_eq!(Protocol::dogstatsd_tag("a:b|c", "d,e#f:g"), "a_b_c:d_e_f:g");
+        }
+
+        #[test]
+        pub fn it_should_ignore_tags_in_plain_statsd() {
+            let data = vec![
+                MetricData::new(None, "HelloCounter".to_owned(), 1, MetricType::Counter(1))
+                    .with_tags(vec![("env".to_owned(), "prod".to_owned())])
+            ];
+
+            assert_eq!(Protocol::StatsD.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE), vec![b"HelloCounter:1|c".to_vec()]);
+        }
+    }
+
+    mod influx {
+        use crate::metric::{metric_test_data, set_test_data, MetricData, MetricType};
+        use crate::protocol::{Protocol, DEFAULT_UDP_PAYLOAD_SIZE};
+
+        #[test]
+        pub fn it_should_write_one_line_per_record() {
+            let data = metric_test_data().to_vec();
+
+            let data = Protocol::InfluxLine.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data.len(), 1);
+            assert_eq!(
+                String::from_utf8_lossy(&data[0]),
+                "test.HelloTimer elapsed_ms=1005i 1000000000\n\
+                test.HelloCounter count=12i 2000000000\n\
+                test.HelloGauge delta=13i 3000000000\n\
+                test.HelloGauge delta=-2i 4000000000\n"
+            );
+        }
+
+        #[test]
+        pub fn it_should_count_unique_set_members() {
+            let data = set_test_data().to_vec();
+
+            let data = Protocol::InfluxLine.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(
+                String::from_utf8_lossy(&data[0]),
+                "test.HelloSet value=2i 7000000000\n\
+                test.HelloCounter count=12i 2000000000\n\
+                test.OtherSet value=1i 6000000000\n"
+            );
+        }
+
+        #[test]
+        pub fn it_should_escape_measurements_and_tags() {
+            let data = vec![
+                MetricData::new(None, "Hello Counter,1".to_owned(), 1, MetricType::Counter(1))
+                    .with_tags(vec![
+                        ("region".to_owned(), "us east".to_owned()),
+                        ("a=b".to_owned(), "c,d".to_owned()),
+                        ("empty".to_owned(), "".to_owned()),
+                    ])
+            ];
+
+            let data = Protocol::InfluxLine.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(
+                String::from_utf8_lossy(&data[0]),
+                "Hello\\ Counter\\,1,a\\=b=c\\,d,region=us\\ east count=1i 1000000000\n"
+            );
+        }
+    }
+
+    mod opentsdb {
+        use crate::metric::{metric_test_data, GaugeOptions, MetricData, MetricType};
+        use crate::protocol::{OpenTSDBMapping, Protocol, DEFAULT_UDP_PAYLOAD_SIZE};
+
+        #[test]
+        pub fn it_should_put_one_line_per_record() {
+            let data = metric_test_data().to_vec();
+            let protocol = Protocol::OpenTSDB(OpenTSDBMapping::new());
+
+            let data = protocol.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data.len(), 1);
+            assert_eq!(
+                String::from_utf8_lossy(&data[0]),
+                "put test.HelloTimer 1000 1005 source=metrical\n\
+                put test.HelloCounter 2000 12 source=metrical\n"
+            );
+        }
+
+        #[test]
+        pub fn it_should_skip_relative_gauge_changes() {
+            let gauge = |occurred, change| MetricData::new(
+                Some("test".to_owned()), "HelloGauge".to_owned(), occurred, MetricType::Gauge(change)
+            );
+            let data = vec![
+                gauge(1, GaugeOptions::Increase(13)),
+                gauge(2, GaugeOptions::Set(7)),
+                gauge(3, GaugeOptions::Decrease(2)),
+            ];
+
+            let data = Protocol::OpenTSDB(OpenTSDBMapping::new()).serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data, vec![b"put test.HelloGauge 2000 7 source=metrical\n".to_vec()]);
+        }
+
+        #[test]
+        pub fn it_should_map_leading_path_segments_to_tags() {
+            let mapping = OpenTSDBMapping::new()
+                .path_tag("env".to_owned())
+                .path_tag("host".to_owned())
+                .default_tag("dc".to_owned(), "east".to_owned())
+                .default_tag("host".to_owned(), "unknown".to_owned());
+
+            let metric = MetricData::new(
+                Some("prod.web01.api".to_owned()), "requests".to_owned(), 1, MetricType::Counter(3)
+            ).with_tags(vec![("route".to_owned(), "/users list".to_owned())]);
+
+            let (name, tags) = mapping.map(&metric);
+            let tags: Vec<(String, String)> = tags.into_iter().collect();
+
+            assert_eq!(name, "api.requests");
+            assert_eq!(tags, vec![
+                ("dc".to_owned(), "east".to_owned()),
+                ("env".to_owned(), "prod".to_owned()),
+                ("host".to_owned(), "web01".to_owned()),
+                ("route".to_owned(), "/users_list".to_owned()),
+            ]);
+        }
+
+        #[test]
+        pub fn it_should_keep_a_segment_for_the_metric_name() {
+            let mapping = OpenTSDBMapping::new()
+                .path_tag("env".to_owned())
+                .path_tag("host".to_owned());
+
+            let metric = MetricData::new(Some("prod".to_owned()), "requests".to_owned(), 1, MetricType::Counter(3));
+            let (name, tags) = mapping.map(&metric);
+
+            assert_eq!(name, "requests");
+            assert_eq!(tags.get("env").map(String::as_str), Some("prod"));
+            assert_eq!(tags.get("host"), None);
+        }
     }
 
     mod statsd {
-        use crate::metric::metric_test_data;
-        use crate::protocol::Protocol;
+        use crate::metric::{metric_test_data, set_test_data, histogram_test_data, MetricData, MetricType};
+        use crate::protocol::{NetworkProtocol, Protocol, DEFAULT_UDP_PAYLOAD_SIZE};
+
+        #[test]
+        pub fn it_should_end_stream_packets_with_a_newline() {
+            let packet = b"a:1|c\nb:1|c".to_vec();
+
+            assert_eq!(Protocol::StatsD.frame(packet.clone(), NetworkProtocol::UDP), packet);
+            assert_eq!(Protocol::StatsD.frame(packet.clone(), NetworkProtocol::TCP), b"a:1|c\nb:1|c\n".to_vec());
+            assert_eq!(Protocol::DogStatsD.frame(packet, NetworkProtocol::TCP), b"a:1|c\nb:1|c\n".to_vec());
+        }
+
+        #[cfg(unix)]
+        #[test]
+        pub fn it_should_prefix_dogstatsd_unix_streams_with_the_length() {
+            let packet = b"a:1|c".to_vec();
+
+            assert_eq!(Protocol::DogStatsD.frame(packet.clone(), NetworkProtocol::UnixDatagram), packet);
+            assert_eq!(Protocol::DogStatsD.frame(packet.clone(), NetworkProtocol::UnixStream), b"\x06\0\0\0a:1|c\n".to_vec());
+            assert_eq!(Protocol::StatsD.frame(packet, NetworkProtocol::UnixStream), b"a:1|c\n".to_vec());
+        }
+
+        #[test]
+        pub fn it_should_send_every_set_member() {
+            let data = set_test_data().to_vec();
+            let protocol = Protocol::StatsD;
+
+            let data = protocol.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data.len(), 1);
+            assert_eq!(data[0].to_vec(), b"test.HelloSet:alice|s\ntest.HelloCounter:12|c\ntest.OtherSet:7|s\ntest.HelloSet:bob|s\ntest.HelloSet:alice|s".to_vec());
+        }
+
+        #[test]
+        pub fn it_should_replace_framing_characters_in_set_members() {
+            let metric = MetricData::new(
+                Some("test".to_owned()), "HelloSet".to_owned(), 1, MetricType::Set("a:1|c\nb".to_owned())
+            );
+
+            let data = Protocol::StatsD.serialize(vec![metric], DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data, vec![b"test.HelloSet:a_1_c_b|s".to_vec()]);
+        }
+
+        #[test]
+        pub fn it_should_send_every_histogram_value() {
+            let data = histogram_test_data().to_vec();
+            let protocol = Protocol::StatsD;
+
+            let data = protocol.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data.len(), 1);
+            assert_eq!(data[0].to_vec(), b"test.HelloHistogram:8|h\ntest.HelloCounter:12|c\ntest.HelloHistogram:2|h\ntest.HelloHistogram:4|h".to_vec());
+        }
+
+        #[test]
+        pub fn it_should_send_distributions() {
+            let data: Vec<MetricData> = histogram_test_data().iter().cloned().map(|metric| {
+                match metric.metric().clone() {
+                    MetricType::Histogram(value) => metric.with_metric(MetricType::Distribution(value)),
+                    _ => metric
+                }
+            }).collect();
+
+            let data = Protocol::StatsD.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert_eq!(data[0].to_vec(), b"test.HelloHistogram:8|d\ntest.HelloCounter:12|c\ntest.HelloHistogram:2|d\ntest.HelloHistogram:4|d".to_vec());
+        }
 
         #[test]
         pub fn it_should_construct_properly() {
             let data = metric_test_data().to_vec();
             let protocol = Protocol::StatsD;
 
-            let data = protocol.serialize(data);
+            let data = protocol.serialize(data, DEFAULT_UDP_PAYLOAD_SIZE);
 
             assert_eq!(data.len(), 1);
             assert_eq!(data[0].to_vec(), b"test.HelloTimer:1005|ms\ntest.HelloCounter:12|c\ntest.HelloGauge:+13|g\ntest.HelloGauge:-2|g".to_vec());
         }
     }
+
+    mod chunking {
+        use crate::configuration::local_test_configuration;
+        use crate::metric::{metric_test_data, MetricData, MetricType};
+        use crate::protocol::{Compression, NetworkProtocol, Protocol, DEFAULT_UDP_PAYLOAD_SIZE};
+        use std::io::Read;
+        use std::net::TcpListener;
+        use std::time::Duration;
+
+        fn long_counters(amount: u32) -> Vec<MetricData> {
+            (0..amount).map(|i| MetricData::new(
+                Some("a.rather.long.namespace.for.a.service.running.in.production".to_owned()),
+                format!("requests_{}", i),
+                1,
+                MetricType::Counter(i)
+            )).collect()
+        }
+
+        #[test]
+        pub fn it_should_fill_packets_up_to_the_payload_size() {
+            let data = Protocol::StatsD.serialize(metric_test_data().to_vec(), 50);
+
+            assert_eq!(data, vec![
+                b"test.HelloTimer:1005|ms\ntest.HelloCounter:12|c".to_vec(),
+                b"test.HelloGauge:+13|g\ntest.HelloGauge:-2|g".to_vec(),
+            ]);
+        }
+
+        #[test]
+        pub fn it_should_send_oversized_records_alone() {
+            let data = Protocol::StatsD.serialize(metric_test_data().to_vec(), 10);
+
+            assert_eq!(data.len(), 4);
+            assert_eq!(data[0], b"test.HelloTimer:1005|ms".to_vec());
+        }
+
+        #[test]
+        pub fn it_should_never_split_records() {
+            let data = Protocol::InfluxLine.serialize(long_counters(100), DEFAULT_UDP_PAYLOAD_SIZE);
+
+            assert!(data.len() > 1);
+            assert!(data.iter().all(|packet| packet.len() <= DEFAULT_UDP_PAYLOAD_SIZE));
+            assert!(data.iter().all(|packet| packet.ends_with(b"000000000\n")));
+
+            let lines: usize = data.iter().map(|packet| packet.iter().filter(|b| **b == b'\n').count()).sum();
+            assert_eq!(lines, 100);
+        }
+
+        #[test]
+        pub fn it_should_frame_graphite_records_over_tcp() -> std::io::Result<()> {
+            let carbon = TcpListener::bind("127.0.0.1:0")?;
+            let protocol = Protocol::Graphite(Compression::Uncompressed);
+            let mut configuration = local_test_configuration(carbon.local_addr()?.port(), protocol, NetworkProtocol::TCP)
+                .max_payload_size(Some(100))
+                .build()
+                .unwrap();
+
+            configuration.send((0..30).map(|i| MetricData::new(
+                None, format!("HelloCounter{}", i), 1, MetricType::Counter(i)
+            )).collect());
+            drop(configuration);
+
+            let (mut stream, _) = carbon.accept()?;
+            stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+            let mut received = String::new();
+            stream.read_to_string(&mut received)?;
+
+            // Carbon splits the stream on newlines, and expects `path value timestamp` on each line
+            assert!(received.ends_with('\n'));
+            let lines: Vec<&str> = received.lines().collect();
+            assert_eq!(lines.len(), 30);
+            for (i, line) in lines.into_iter().enumerate() {
+                assert_eq!(line, format!("HelloCounter{} {} 1", i, i));
+            }
+
+            Ok(())
+        }
+
+        #[test]
+        #[cfg(feature = "pickle")]
+        pub fn it_should_chunk_pickled_records() -> Result<(), Box<dyn std::error::Error>> {
+            use crate::protocol::Compression;
+
+            let data = Protocol::Graphite(Compression::Pickled).serialize(long_counters(200), 512);
+
+            assert!(data.len() > 1);
+            assert!(data.iter().all(|packet| packet.len() <= 512));
+
+            let mut records = 0;
+            for packet in data {
+                let unpickled: Vec<(String, (String, String))> = serde_pickle::from_slice(&packet[4..])?;
+                records += unpickled.len();
+            }
+            assert_eq!(records, 200);
+
+            Ok(())
+        }
+    }
 }
\ No newline at end of file
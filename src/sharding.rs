@@ -0,0 +1,265 @@
+use crate::metric::MetricData;
+use crate::protocol::Protocol;
+
+/// The number of positions each node takes on the ring, as in carbon
+const REPLICAS: usize = 100;
+
+/// A carbon-cache instance: its server, and the optional instance name carbon-relay is
+/// configured with, such as `a` in `127.0.0.1:2104:a`
+pub type CarbonNode = (String, Option<String>);
+
+/// The consistent hash ring of carbon-relay's `consistent-hashing` router.
+///
+/// Series are placed exactly as carbon places them (carbon's `carbon_ch` hash), so metrical
+/// can shard series across carbon-cache instances without a relay, and a series always lands
+/// on the node carbon-relay and graphite-web expect it on.
+pub struct ConsistentHashRing {
+    /// Positions on the ring and the index of the node placed there, sorted by position
+    ring: Vec<(u32, usize)>,
+    nodes: usize,
+    replication_factor: usize
+}
+
+impl ConsistentHashRing {
+    pub fn new(nodes: &[CarbonNode], replication_factor: usize) -> ConsistentHashRing {
+        let mut ring: Vec<(u32, usize)> = Vec::with_capacity(nodes.len() * REPLICAS);
+
+        for (index, node) in nodes.iter().enumerate() {
+            let name = ConsistentHashRing::node_name(node);
+
+            for replica in 0..REPLICAS {
+                // Carbon moves a replica which collides with an earlier one to the next free
+                // position, so the order nodes are added in matters.
+                let mut position = ConsistentHashRing::position(&format!("{}:{}", name, replica));
+                let mut slot = ring.partition_point(|(taken, _)| *taken < position);
+                while ring.get(slot).is_some_and(|(taken, _)| *taken == position) {
+                    position += 1;
+                    slot += 1;
+                }
+
+                ring.insert(slot, (position, index));
+            }
+        }
+
+        ConsistentHashRing { ring, nodes: nodes.len(), replication_factor }
+    }
+
+    /// The position of a key on the ring: the first 16 bits of its MD5 digest
+    fn position(key: &str) -> u32 {
+        let digest = md5::compute(key.as_bytes());
+        u32::from(digest[0]) << 8 | u32::from(digest[1])
+    }
+
+    /// Carbon names a node by formatting its `(server, instance)` tuple with Python's `str`,
+    /// such as `('127.0.0.1', 'a')` or `('127.0.0.1', None)`.
+    fn node_name(node: &CarbonNode) -> String {
+        match &node.1 {
+            Some(instance) => format!("({}, {})", python_repr(&node.0), python_repr(instance)),
+            None => format!("({}, None)", python_repr(&node.0))
+        }
+    }
+
+    /// The indices of the nodes a key is stored on, in order of preference. At most
+    /// `replication_factor` distinct nodes are returned.
+    pub fn nodes(&self, key: &str) -> Vec<usize> {
+        if self.ring.is_empty() {
+            return Vec::new();
+        }
+
+        let position = ConsistentHashRing::position(key);
+        let mut index = self.ring.partition_point(|(taken, _)| *taken < position) % self.ring.len();
+
+        if self.nodes == 1 {
+            return vec![self.ring[index].1];
+        }
+
+        // Like carbon, the walk around the ring stops one entry short of where it started.
+        let last = (index + self.ring.len() - 1) % self.ring.len();
+        let mut found: Vec<usize> = Vec::with_capacity(self.replication_factor);
+        while found.len() < self.nodes.min(self.replication_factor) && index != last {
+            let node = self.ring[index].1;
+            if !found.contains(&node) {
+                found.push(node);
+            }
+            index = (index + 1) % self.ring.len();
+        }
+
+        found
+    }
+
+    /// Splits the records into one batch per node, keyed by their Graphite path
+    pub fn distribute(&self, data: Vec<MetricData>) -> Vec<Vec<MetricData>> {
+        let mut batches = vec![Vec::new(); self.nodes];
+
+        for metric in data {
+            for node in self.nodes(&Protocol::graphite_path(&metric)) {
+                batches[node].push(metric.clone());
+            }
+        }
+
+        batches
+    }
+}
+
+/// Quotes a string the way Python's `repr` quotes printable ASCII strings
+fn python_repr(value: &str) -> String {
+    let quote = if value.contains('\'') && !value.contains('"') { '"' } else { '\'' };
+
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push(quote);
+    for character in value.chars() {
+        if character == '\\' || character == quote {
+            quoted.push('\\');
+        }
+        quoted.push(character);
+    }
+    quoted.push(quote);
+
+    quoted
+}
+
+#[cfg(test)]
+mod test {
+    use crate::configuration::ConfigurationBuilder;
+    use crate::metric::{MetricData, MetricType};
+    use crate::protocol::{Compression, NetworkProtocol, Protocol};
+    use crate::sharding::{python_repr, CarbonNode, ConsistentHashRing};
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    fn nodes() -> Vec<CarbonNode> {
+        vec![
+            ("127.0.0.1".to_owned(), Some("a".to_owned())),
+            ("127.0.0.1".to_owned(), Some("b".to_owned())),
+            ("10.0.0.2".to_owned(), None),
+        ]
+    }
+
+    fn record(name: &str) -> MetricData {
+        MetricData::new(None, name.to_owned(), 1, MetricType::Counter(1))
+    }
+
+    /// A configuration sharding between two carbon caches on the loopback interface
+    fn shards(first: &TcpListener, second: &TcpListener) -> std::io::Result<ConfigurationBuilder> {
+        Ok(ConfigurationBuilder::new()
+            .shard("127.0.0.1".to_owned(), first.local_addr()?.port(), Some("a".to_owned()))
+            .shard("127.0.0.1".to_owned(), second.local_addr()?.port(), Some("b".to_owned()))
+            .protocol(Protocol::Graphite(Compression::Uncompressed))
+            .network_protocol(NetworkProtocol::TCP))
+    }
+
+    fn read_all(listener: &TcpListener) -> std::io::Result<String> {
+        let (mut stream, _) = listener.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+
+        let mut received = String::new();
+        stream.read_to_string(&mut received)?;
+        Ok(received)
+    }
+
+    #[test]
+    pub fn it_should_build_the_same_ring_as_carbon() {
+        let ring = ConsistentHashRing::new(&nodes(), 1);
+
+        // The first entries of carbon's ring for the same nodes
+        assert_eq!(&ring.ring[..5], &[(398, 0), (437, 2), (474, 0), (1518, 2), (1884, 1)]);
+        assert_eq!(ring.ring.len(), 300);
+    }
+
+    #[test]
+    pub fn it_should_place_series_on_the_same_nodes_as_carbon() {
+        let ring = ConsistentHashRing::new(&nodes(), 3);
+
+        // The nodes carbon's ConsistentHashRing.get_nodes returns for each key, in order
+        let placements: &[(&str, [usize; 3])] = &[
+            ("servers.web01.cpu.user", [2, 0, 1]),
+            ("servers.web02.cpu.user", [0, 2, 1]),
+            ("servers.db01.disk.used", [1, 2, 0]),
+            ("app.requests.count", [0, 1, 2]),
+            ("app.latency.p99", [2, 1, 0]),
+            ("app.requests;env=prod;route=users", [0, 1, 2]),
+        ];
+
+        for (key, expected) in placements {
+            assert_eq!(ring.nodes(key), expected.to_vec(), "{}", key);
+        }
+    }
+
+    #[test]
+    pub fn it_should_limit_placements_to_the_replication_factor() {
+        let ring = ConsistentHashRing::new(&nodes(), 2);
+        assert_eq!(ring.nodes("servers.web01.cpu.user"), vec![2, 0]);
+
+        let ring = ConsistentHashRing::new(&nodes()[..1], 3);
+        assert_eq!(ring.nodes("servers.web01.cpu.user"), vec![0]);
+    }
+
+    #[test]
+    pub fn it_should_distribute_records_by_path() {
+        let ring = ConsistentHashRing::new(&nodes(), 1);
+
+        let batches = ring.distribute(vec![
+            record("servers.web01.cpu.user"),
+            record("servers.web02.cpu.user"),
+            record("servers.db01.disk.used"),
+        ]);
+
+        assert_eq!(batches, vec![
+            vec![record("servers.web02.cpu.user")],
+            vec![record("servers.db01.disk.used")],
+            vec![record("servers.web01.cpu.user")],
+        ]);
+    }
+
+    #[test]
+    pub fn it_should_quote_like_python() {
+        assert_eq!(python_repr("127.0.0.1"), "'127.0.0.1'");
+        assert_eq!(python_repr("it's"), "\"it's\"");
+        assert_eq!(python_repr("a'b\"c"), "'a\\'b\"c'");
+        assert_eq!(python_repr("a\\b"), "'a\\\\b'");
+    }
+
+    #[test]
+    pub fn it_should_shard_series_across_carbon_caches() -> std::io::Result<()> {
+        let (first, second) = (TcpListener::bind("127.0.0.1:0")?, TcpListener::bind("127.0.0.1:0")?);
+        let mut configuration = shards(&first, &second)?.build().unwrap();
+
+        configuration.send((0..4).map(|i| record(&format!("servers.web{:02}.cpu.user", i))).collect());
+        drop(configuration);
+
+        // Placed as carbon-relay places them with DESTINATIONS = 127.0.0.1:..:a, 127.0.0.1:..:b
+        assert_eq!(read_all(&first)?, "servers.web01.cpu.user 1 1\nservers.web02.cpu.user 1 1\n");
+        assert_eq!(read_all(&second)?, "servers.web00.cpu.user 1 1\nservers.web03.cpu.user 1 1\n");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_replicate_series_across_carbon_caches() -> std::io::Result<()> {
+        let (first, second) = (TcpListener::bind("127.0.0.1:0")?, TcpListener::bind("127.0.0.1:0")?);
+        let mut configuration = shards(&first, &second)?.replication_factor(2).build().unwrap();
+
+        configuration.send(vec![record("servers.web00.cpu.user")]);
+        drop(configuration);
+
+        assert_eq!(read_all(&first)?, "servers.web00.cpu.user 1 1\n");
+        assert_eq!(read_all(&second)?, "servers.web00.cpu.user 1 1\n");
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_reject_invalid_shards() {
+        let shards = || ConfigurationBuilder::new()
+            .shard("127.0.0.1".to_owned(), 2104, Some("a".to_owned()))
+            .network_protocol(NetworkProtocol::TCP);
+        let graphite = || shards().protocol(Protocol::Graphite(Compression::Uncompressed));
+
+        assert!(shards().protocol(Protocol::StatsD).build().is_err());
+        assert!(graphite().host("127.0.0.1".to_owned()).port(2003).build().is_err());
+        assert!(graphite().shard("127.0.0.1".to_owned(), 2204, Some("a".to_owned())).build().is_err());
+        assert!(graphite().replication_factor(0).build().is_err());
+        assert!(graphite().shard("127.0.0.1".to_owned(), 2204, Some("b".to_owned())).build().is_ok());
+    }
+}
@@ -1,24 +1,24 @@
-use crate::collector::Collector;
+use crate::client::MetricsClient;
+use crate::collector::NullCollector;
+use crate::MetricalError;
+use std::sync::{Arc, OnceLock};
 
-static mut COLLECTOR: Option<&'static mut dyn Collector> = None;
+static CLIENT: OnceLock<MetricsClient> = OnceLock::new();
+static NULL_CLIENT: OnceLock<MetricsClient> = OnceLock::new();
 
-pub fn collector() -> &'static mut &'static mut dyn Collector {
-    // Simply returns a reference to the collector. This is unsafe because the collector
-    // can technically be mutated during the holding of the reference. In reality, the Collector
-    // can only be set once, before any calls to this function, so this shouldn't ever happen.
-    unsafe {
-        COLLECTOR.as_mut().unwrap()
+/// Returns the process wide client used by the free functions in the crate root.
+///
+/// Until metrical has been initialized, metrics are discarded by a `NullCollector`.
+pub fn client() -> &'static MetricsClient {
+    match CLIENT.get() {
+        Some(client) => client,
+        None => NULL_CLIENT.get_or_init(|| MetricsClient::from_collector(Arc::new(NullCollector)))
     }
 }
 
-pub fn set_boxed_collector(collector: Box<dyn Collector>) {
-    let static_collector = Box::leak(collector);
-
-    set_collector(static_collector)
+/// Installs the process wide client. The client can only be set once.
+pub fn set_client(client: MetricsClient) -> Result<(), MetricalError> {
+    CLIENT
+        .set(client)
+        .map_err(|_| MetricalError::AlreadyInitialized)
 }
-
-pub fn set_collector(collector: &'static mut dyn Collector) {
-    unsafe {
-        COLLECTOR = Some(collector)
-    }
-}
\ No newline at end of file
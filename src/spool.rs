@@ -0,0 +1,370 @@
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const SEGMENT_EXTENSION: &str = "spool";
+const LENGTH_SIZE: usize = 4;
+
+/// Options for spooling payloads to disk while the server cannot be reached.
+///
+/// Payloads which fail to send are appended to segment files in the directory, and replayed
+/// in order, exactly as they were serialized, once the server can be reached again. The
+/// spool is kept across restarts.
+///
+/// # Example
+/// ```no_run
+/// use metrical::SpoolOptions;
+/// use std::path::PathBuf;
+///
+/// let options = SpoolOptions::new(PathBuf::from("/var/spool/metrical"))
+///     .segment_size(1024 * 1024)
+///     .max_size(64 * 1024 * 1024);
+/// ```
+#[derive(Clone)]
+pub struct SpoolOptions {
+    directory: PathBuf,
+    segment_size: u64,
+    max_size: u64
+}
+
+impl SpoolOptions {
+    pub fn new(directory: PathBuf) -> Self {
+        SpoolOptions {
+            directory,
+            segment_size: 1024 * 1024,
+            max_size: 100 * 1024 * 1024
+        }
+    }
+
+    /// The size in bytes a segment file grows to before a new one is started. Defaults to 1 MiB.
+    pub fn segment_size(mut self, bytes: u64) -> Self {
+        self.segment_size = bytes;
+        self
+    }
+
+    /// The size in bytes the spool may take on disk. The oldest segments are dropped to stay
+    /// under it. Defaults to 100 MiB.
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = bytes;
+        self
+    }
+
+    /// Opens the spool in the directory, or in a subdirectory of it
+    pub(crate) fn open(&self, subdirectory: Option<&str>) -> std::io::Result<Spool> {
+        let directory = match subdirectory {
+            Some(name) => self.directory.join(name),
+            None => self.directory.clone()
+        };
+
+        Spool::open(directory, self.segment_size, self.max_size)
+    }
+}
+
+/// Payloads waiting on disk to be sent, oldest first.
+///
+/// Each segment file holds payloads framed by their length as a big endian `u32`. Segments
+/// are named by an increasing sequence number.
+pub struct Spool {
+    directory: PathBuf,
+    segment_size: u64,
+    max_size: u64,
+    /// The sequence numbers of the segments on disk, oldest first
+    segments: VecDeque<u64>,
+    /// The segment payloads are appended to. Segments left by an earlier process are never
+    /// appended to, as they may end with a record cut short.
+    writing: Option<u64>
+}
+
+impl Spool {
+    pub fn open(directory: PathBuf, segment_size: u64, max_size: u64) -> std::io::Result<Spool> {
+        std::fs::create_dir_all(&directory)?;
+
+        let mut segments: Vec<u64> = std::fs::read_dir(&directory)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|extension| extension == SEGMENT_EXTENSION))
+            .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+            .collect();
+        segments.sort_unstable();
+
+        Ok(Spool { directory, segment_size, max_size, segments: segments.into(), writing: None })
+    }
+
+    fn path(&self, segment: u64) -> PathBuf {
+        self.directory.join(format!("{:020}.{}", segment, SEGMENT_EXTENSION))
+    }
+
+    fn segment_len(&self, segment: u64) -> u64 {
+        std::fs::metadata(self.path(segment)).map_or(0, |metadata| metadata.len())
+    }
+
+    /// Appends the payloads after every payload already spooled
+    pub fn append<I>(&mut self, payloads: I) -> std::io::Result<()> where I: IntoIterator<Item=Vec<u8>> {
+        let mut payloads = payloads.into_iter().peekable();
+        if payloads.peek().is_none() {
+            return Ok(());
+        }
+
+        let mut segment = match self.writing {
+            Some(segment) => segment,
+            None => self.start_segment()
+        };
+        let mut len = self.segment_len(segment);
+        let mut file = OpenOptions::new().create(true).append(true).open(self.path(segment))?;
+
+        for payload in payloads {
+            let record_len = (LENGTH_SIZE + payload.len()) as u64;
+            if len > 0 && len + record_len > self.segment_size {
+                file.sync_data()?;
+                segment = self.start_segment();
+                len = 0;
+                file = OpenOptions::new().create(true).append(true).open(self.path(segment))?;
+            }
+
+            file.write_all(&Spool::record(&payload))?;
+            len += record_len;
+        }
+        file.sync_data()?;
+
+        self.enforce_max_size();
+        Ok(())
+    }
+
+    fn start_segment(&mut self) -> u64 {
+        let segment = self.segments.back().map_or(0, |newest| newest + 1);
+        self.segments.push_back(segment);
+        self.writing = Some(segment);
+        segment
+    }
+
+    fn remove_oldest(&mut self) {
+        if let Some(oldest) = self.segments.pop_front() {
+            let _ = std::fs::remove_file(self.path(oldest));
+            if self.writing == Some(oldest) {
+                self.writing = None;
+            }
+        }
+    }
+
+    fn record(payload: &[u8]) -> Vec<u8> {
+        let mut record = vec![0; LENGTH_SIZE];
+        BigEndian::write_u32(&mut record, payload.len() as u32);
+        record.extend_from_slice(payload);
+        record
+    }
+
+    /// Drops the oldest segments until the spool fits in its maximum size. The segment
+    /// being written to is always kept.
+    fn enforce_max_size(&mut self) {
+        let mut size: u64 = self.segments.iter().map(|segment| self.segment_len(*segment)).sum();
+
+        while size > self.max_size && self.segments.len() > 1 {
+            size -= self.segments.front().map_or(0, |oldest| self.segment_len(*oldest));
+            self.remove_oldest();
+        }
+    }
+
+    /// Reads the payloads of a segment. A record cut short, such as by a crash while it was
+    /// written, is ignored.
+    fn read(path: &Path) -> std::io::Result<Vec<Vec<u8>>> {
+        let data = std::fs::read(path)?;
+
+        let mut payloads = Vec::new();
+        let mut offset = 0;
+        while offset + LENGTH_SIZE <= data.len() {
+            let len = BigEndian::read_u32(&data[offset..]) as usize;
+            let start = offset + LENGTH_SIZE;
+            if start + len > data.len() {
+                break;
+            }
+
+            payloads.push(data[start..start + len].to_vec());
+            offset = start + len;
+        }
+
+        Ok(payloads)
+    }
+
+    /// Sends the spooled payloads oldest first, stopping at the first which fails to send.
+    /// Returns whether every spooled payload was sent.
+    pub fn replay<F>(&mut self, mut send: F) -> bool where F: FnMut(&[u8]) -> std::io::Result<()> {
+        while let Some(segment) = self.segments.front().copied() {
+            let path = self.path(segment);
+
+            // A segment which cannot be read could never be replayed, so it is dropped.
+            let payloads = Spool::read(&path).unwrap_or_default();
+
+            let sent = payloads.iter().take_while(|payload| send(payload).is_ok()).count();
+            if sent < payloads.len() {
+                // Only the payloads which were not sent are kept, so none is sent twice.
+                if Spool::rewrite(&path, &payloads[sent..]).is_err() {
+                    self.remove_oldest();
+                }
+                return false;
+            }
+
+            self.remove_oldest();
+        }
+
+        true
+    }
+
+    /// Replaces the segment with the payloads, atomically
+    fn rewrite(path: &Path, payloads: &[Vec<u8>]) -> std::io::Result<()> {
+        let temporary = path.with_extension("tmp");
+
+        let mut file = File::create(&temporary)?;
+        for payload in payloads {
+            file.write_all(&Spool::record(payload))?;
+        }
+        file.sync_data()?;
+
+        std::fs::rename(&temporary, path)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::configuration::local_test_configuration;
+    use crate::metric::{MetricData, MetricType};
+    use crate::protocol::{Compression, NetworkProtocol, Protocol};
+    use crate::spool::{Spool, SpoolOptions};
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("metrical-spool-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn payloads(count: usize) -> Vec<Vec<u8>> {
+        (0..count).map(|i| format!("HelloCounter {} {}\n", i, 1600000000 + i).into_bytes()).collect()
+    }
+
+    fn replay_all(spool: &mut Spool) -> Vec<Vec<u8>> {
+        let mut replayed = Vec::new();
+        assert!(spool.replay(|payload| {
+            replayed.push(payload.to_vec());
+            Ok(())
+        }));
+        replayed
+    }
+
+    #[test]
+    pub fn it_should_replay_payloads_in_order() -> std::io::Result<()> {
+        let mut spool = Spool::open(directory("order"), 64, u64::MAX)?;
+        spool.append(payloads(3))?;
+        spool.append(payloads(10).split_off(3))?;
+
+        // Every segment holds at most 64 bytes, except a single larger record
+        assert!(spool.segments.len() > 1);
+        assert_eq!(replay_all(&mut spool), payloads(10));
+        assert!(spool.segments.is_empty());
+        assert_eq!(std::fs::read_dir(&spool.directory)?.count(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_keep_the_spool_across_restarts() -> std::io::Result<()> {
+        let directory = directory("restart");
+
+        let mut spool = Spool::open(directory.clone(), 64, u64::MAX)?;
+        spool.append(payloads(5))?;
+        drop(spool);
+
+        let mut spool = Spool::open(directory, 64, u64::MAX)?;
+        spool.append(payloads(7).split_off(5))?;
+        assert_eq!(replay_all(&mut spool), payloads(7));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_keep_payloads_which_fail_to_replay() -> std::io::Result<()> {
+        let directory = directory("failure");
+        let mut spool = Spool::open(directory.clone(), 1024, u64::MAX)?;
+        spool.append(payloads(5))?;
+
+        let mut sent = Vec::new();
+        assert!(!spool.replay(|payload| {
+            if sent.len() == 2 {
+                return Err(std::io::Error::from(std::io::ErrorKind::ConnectionRefused));
+            }
+            sent.push(payload.to_vec());
+            Ok(())
+        }));
+        assert_eq!(sent, payloads(2));
+
+        let mut spool = Spool::open(directory, 1024, u64::MAX)?;
+        assert_eq!(replay_all(&mut spool), payloads(5).split_off(2));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_drop_the_oldest_segments_over_the_maximum_size() -> std::io::Result<()> {
+        let mut spool = Spool::open(directory("max-size"), 64, 128)?;
+        spool.append(payloads(20))?;
+
+        let replayed = replay_all(&mut spool);
+        assert!(replayed.len() < 20);
+        assert_eq!(replayed, payloads(20).split_off(20 - replayed.len()));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_ignore_a_torn_record() -> std::io::Result<()> {
+        let mut spool = Spool::open(directory("torn"), 1024, u64::MAX)?;
+        spool.append(payloads(2))?;
+
+        let mut file = std::fs::OpenOptions::new().append(true).open(spool.path(0))?;
+        file.write_all(&[0, 0, 0, 40, b'H'])?;
+
+        let mut spool = Spool::open(spool.directory.clone(), 1024, u64::MAX)?;
+        spool.append(payloads(3).split_off(2))?;
+        assert_eq!(replay_all(&mut spool), payloads(3));
+
+        Ok(())
+    }
+
+    #[test]
+    pub fn it_should_replay_spooled_payloads_after_a_restart() -> std::io::Result<()> {
+        let directory = directory("restart");
+        let port = TcpListener::bind("127.0.0.1:0")?.local_addr()?.port();
+        let record = |name: &str, count| MetricData::new(None, name.to_owned(), 1, MetricType::Counter(count));
+        let configuration = || {
+            local_test_configuration(port, Protocol::Graphite(Compression::Uncompressed), NetworkProtocol::TCP)
+                .spool(SpoolOptions::new(directory.clone()))
+                .build()
+                .unwrap()
+        };
+
+        // Every batch sent while the server is down is spooled, not only the latest
+        let mut spooling = configuration();
+        spooling.send(vec![record("First", 1)]);
+        spooling.send(vec![record("Second", 2)]);
+        drop(spooling);
+
+        let carbon = TcpListener::bind(("127.0.0.1", port))?;
+        let mut replaying = configuration();
+        replaying.send(vec![record("Third", 3)]);
+        drop(replaying);
+
+        let (mut stream, _) = carbon.accept()?;
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut received = String::new();
+        stream.read_to_string(&mut received)?;
+
+        assert_eq!(received, "First 1 1\nSecond 2 1\nThird 3 1\n");
+        assert_eq!(std::fs::read_dir(&directory)?.count(), 0);
+
+        std::fs::remove_dir_all(&directory)
+    }
+}
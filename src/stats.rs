@@ -0,0 +1,156 @@
+use crate::metric::{MetricData, MetricType};
+use std::ops::AddAssign;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// A snapshot of metrical's own activity, see `metrical::stats`.
+///
+/// Every count is cumulative since the client was created.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Metrics added to a buffer
+    pub buffered: u64,
+    /// Metrics taken from a buffer by a flush
+    pub flushed: u64,
+    /// Metrics discarded because a buffer was full
+    pub dropped: u64,
+    /// Packets or stream writes sent to a server
+    pub packets_sent: u64,
+    /// Bytes sent to a server
+    pub bytes_sent: u64,
+    /// Packets or stream writes which could not be sent
+    pub send_errors: u64,
+    /// Unsent payloads discarded, to make room for a newer flush or because the spool could
+    /// not hold them
+    pub payloads_dropped: u64,
+    /// Appends to the spool which failed
+    pub spool_errors: u64,
+    /// Connections reopened after they broke
+    pub reconnects: u64,
+    /// Flushes of a buffer, including flushes with nothing to send
+    pub flushes: u64,
+    /// How long the latest flush took to send its metrics
+    pub flush_latency: Duration
+}
+
+impl AddAssign for Stats {
+    /// Combines the stats of several destinations. The flush latency is the slowest one.
+    fn add_assign(&mut self, other: Stats) {
+        self.buffered += other.buffered;
+        self.flushed += other.flushed;
+        self.dropped += other.dropped;
+        self.packets_sent += other.packets_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.send_errors += other.send_errors;
+        self.payloads_dropped += other.payloads_dropped;
+        self.spool_errors += other.spool_errors;
+        self.reconnects += other.reconnects;
+        self.flushes += other.flushes;
+        self.flush_latency = self.flush_latency.max(other.flush_latency);
+    }
+}
+
+impl Stats {
+    /// The stats as records under the namespace, such as `metrical.bytes_sent`
+    pub fn metrics(&self, namespace: &str) -> Vec<MetricData> {
+        let occurred = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0))
+            .as_secs();
+
+        let values = [
+            ("buffered", self.buffered as f64),
+            ("flushed", self.flushed as f64),
+            ("dropped", self.dropped as f64),
+            ("packets_sent", self.packets_sent as f64),
+            ("bytes_sent", self.bytes_sent as f64),
+            ("send_errors", self.send_errors as f64),
+            ("payloads_dropped", self.payloads_dropped as f64),
+            ("spool_errors", self.spool_errors as f64),
+            ("reconnects", self.reconnects as f64),
+            ("flushes", self.flushes as f64),
+            ("flush_latency_ms", self.flush_latency.as_secs_f64() * 1000.0),
+        ];
+
+        values.iter()
+            .map(|(name, value)| MetricData::new(
+                Some(namespace.to_owned()), (*name).to_owned(), occurred, MetricType::Aggregate(*value)
+            ))
+            .collect()
+    }
+}
+
+/// The counters behind `Stats`, updated by a collector and its configuration
+#[derive(Default)]
+pub struct Telemetry {
+    buffered: AtomicU64,
+    flushed: AtomicU64,
+    dropped: AtomicU64,
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    send_errors: AtomicU64,
+    payloads_dropped: AtomicU64,
+    spool_errors: AtomicU64,
+    reconnects: AtomicU64,
+    flushes: AtomicU64,
+    flush_latency_nanos: AtomicU64
+}
+
+impl Telemetry {
+    pub fn buffered(&self) {
+        self.buffered.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn flushed(&self, records: usize, latency: Duration) {
+        self.flushed.fetch_add(records as u64, Ordering::Relaxed);
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.flush_latency_nanos.store(latency.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Records the outcome of sending a payload of the given size
+    pub fn sent(&self, bytes: usize, result: &std::io::Result<()>) {
+        match result {
+            Ok(()) => {
+                self.packets_sent.fetch_add(1, Ordering::Relaxed);
+                self.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+            },
+            Err(_) => {
+                self.send_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn payloads_dropped(&self, payloads: usize) {
+        self.payloads_dropped.fetch_add(payloads as u64, Ordering::Relaxed);
+    }
+
+    /// Records a failed append of the given number of payloads to the spool, which are lost
+    pub fn spool_failed(&self, payloads: usize) {
+        self.spool_errors.fetch_add(1, Ordering::Relaxed);
+        self.payloads_dropped(payloads);
+    }
+
+    pub fn reconnected(&self, times: u64) {
+        self.reconnects.fetch_add(times, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> Stats {
+        Stats {
+            buffered: self.buffered.load(Ordering::Relaxed),
+            flushed: self.flushed.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            packets_sent: self.packets_sent.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            send_errors: self.send_errors.load(Ordering::Relaxed),
+            payloads_dropped: self.payloads_dropped.load(Ordering::Relaxed),
+            spool_errors: self.spool_errors.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            flushes: self.flushes.load(Ordering::Relaxed),
+            flush_latency: Duration::from_nanos(self.flush_latency_nanos.load(Ordering::Relaxed))
+        }
+    }
+}
@@ -0,0 +1,285 @@
+use crate::MetricalError;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use std::convert::TryFrom;
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// A TLS session over a TCP stream
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+/// Options for TLS encrypted TCP connections, used with `NetworkProtocol::TLS`.
+///
+/// By default the server certificate is verified against the Mozilla root certificates, and
+/// must be valid for the host metrics are sent to.
+///
+/// # Example
+/// ```no_run
+/// use metrical::TlsOptions;
+/// use std::path::PathBuf;
+///
+/// let options = TlsOptions::new()
+///     .ca_file(PathBuf::from("/etc/carbon/ca.pem"))
+///     .client_certificate(PathBuf::from("/etc/carbon/client.pem"), PathBuf::from("/etc/carbon/client.key"))
+///     .server_name("carbon.internal".to_owned());
+/// ```
+#[derive(Clone, Default)]
+pub struct TlsOptions {
+    ca_file: Option<PathBuf>,
+    client_certificate: Option<(PathBuf, PathBuf)>,
+    server_name: Option<String>
+}
+
+impl TlsOptions {
+    pub fn new() -> Self {
+        TlsOptions::default()
+    }
+
+    /// Verify the server certificate against the PEM encoded certificates in the file,
+    /// instead of the Mozilla root certificates
+    pub fn ca_file(mut self, path: PathBuf) -> Self {
+        self.ca_file = Some(path);
+        self
+    }
+
+    /// Authenticate with the PEM encoded certificate chain and private key in the files
+    pub fn client_certificate(mut self, certificate: PathBuf, key: PathBuf) -> Self {
+        self.client_certificate = Some((certificate, key));
+        self
+    }
+
+    /// The name sent with SNI and verified against the server certificate. Defaults to the
+    /// host metrics are sent to.
+    pub fn server_name(mut self, name: String) -> Self {
+        self.server_name = Some(name);
+        self
+    }
+
+    /// Reads the certificates and keys, creating a connector for sessions with the host
+    pub(crate) fn connector(&self, host: &str) -> Result<TlsConnector, MetricalError> {
+        let mut roots = RootCertStore::empty();
+        match &self.ca_file {
+            Some(path) => {
+                let certificates = CertificateDer::pem_file_iter(path)
+                    .map_err(|_| MetricalError::ConfigurationInvalid("Unable to read TLS CA file"))?;
+
+                for certificate in certificates {
+                    let certificate = certificate
+                        .map_err(|_| MetricalError::ConfigurationInvalid("Unable to parse TLS CA file"))?;
+                    roots.add(certificate)
+                        .map_err(|_| MetricalError::ConfigurationInvalid("Invalid certificate in TLS CA file"))?;
+                }
+            },
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned())
+        }
+
+        let builder = ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(|_| MetricalError::ConfigurationInvalid("Unable to configure TLS"))?
+            .with_root_certificates(roots);
+
+        let config = match &self.client_certificate {
+            Some((certificate, key)) => {
+                let chain = CertificateDer::pem_file_iter(certificate)
+                    .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+                    .map_err(|_| MetricalError::ConfigurationInvalid("Unable to read TLS client certificate"))?;
+                let key = PrivateKeyDer::from_pem_file(key)
+                    .map_err(|_| MetricalError::ConfigurationInvalid("Unable to read TLS client key"))?;
+
+                builder.with_client_auth_cert(chain, key)
+                    .map_err(|_| MetricalError::ConfigurationInvalid("Invalid TLS client certificate"))?
+            },
+            None => builder.with_no_client_auth()
+        };
+
+        let name = self.server_name.as_deref().unwrap_or(host);
+        let server_name = ServerName::try_from(name.trim_start_matches('[').trim_end_matches(']').to_owned())
+            .map_err(|_| MetricalError::ConfigurationInvalid("Invalid TLS server name"))?;
+
+        Ok(TlsConnector { config: Arc::new(config), server_name })
+    }
+}
+
+/// Starts TLS sessions with one server
+pub struct TlsConnector {
+    config: Arc<ClientConfig>,
+    server_name: ServerName<'static>
+}
+
+impl TlsConnector {
+    /// Starts a session over the stream, completing the handshake before returning
+    pub fn connect(&self, mut stream: TcpStream) -> std::io::Result<TlsStream> {
+        let mut session = ClientConnection::new(self.config.clone(), self.server_name.clone())
+            .map_err(std::io::Error::other)?;
+
+        while session.is_handshaking() {
+            session.complete_io(&mut stream)?;
+        }
+
+        Ok(StreamOwned::new(session, stream))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use crate::client::MetricsClient;
+    use crate::configuration::local_test_configuration;
+    use crate::connection::Connection;
+    use crate::protocol::{Compression, NetworkProtocol, Protocol};
+    use crate::tls::TlsOptions;
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+    use rustls::server::WebPkiClientVerifier;
+    use rustls::{RootCertStore, ServerConfig, ServerConnection, StreamOwned};
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+
+    /// A self-signed certificate valid for `localhost`, written to PEM files named after `name`
+    pub fn certificate(name: &str) -> (PathBuf, PathBuf) {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+
+        let directory = std::env::temp_dir();
+        let certificate = directory.join(format!("metrical-{}-{}.pem", name, std::process::id()));
+        let key = directory.join(format!("metrical-{}-{}.key", name, std::process::id()));
+        std::fs::write(&certificate, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        (certificate, key)
+    }
+
+    /// Accepts a single TLS session, returning the first line received over it. Clients must
+    /// present a certificate signed by `client_ca`, when given.
+    pub fn serve(certificate: &(PathBuf, PathBuf), client_ca: Option<&PathBuf>) -> (u16, JoinHandle<Option<String>>) {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap();
+
+        let builder = match client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                roots.add(CertificateDer::from_pem_file(path).unwrap()).unwrap();
+                let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                    .build()
+                    .unwrap();
+                builder.with_client_cert_verifier(verifier)
+            },
+            None => builder.with_no_client_auth()
+        };
+
+        let config = Arc::new(builder.with_single_cert(
+            vec![CertificateDer::from_pem_file(&certificate.0).unwrap()],
+            PrivateKeyDer::from_pem_file(&certificate.1).unwrap()
+        ).unwrap());
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let handle = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().ok()?;
+            stream.set_read_timeout(Some(Duration::from_secs(5))).ok()?;
+
+            let session = ServerConnection::new(config).ok()?;
+            let mut reader = BufReader::new(StreamOwned::new(session, stream));
+
+            let mut line = String::new();
+            reader.read_line(&mut line).ok()?;
+            Some(line)
+        });
+
+        (port, handle)
+    }
+
+    fn connection(port: u16, options: TlsOptions) -> Connection {
+        let connector = options.connector("127.0.0.1").unwrap();
+        Connection::tls("127.0.0.1".to_owned(), port, connector)
+    }
+
+    #[test]
+    pub fn it_should_verify_the_server_against_the_ca_file() {
+        let server = certificate("server-ca");
+        let (port, handle) = serve(&server, None);
+
+        let options = TlsOptions::new()
+            .ca_file(server.0.clone())
+            .server_name("localhost".to_owned());
+
+        connection(port, options).send(b"HelloCounter 1 1\n").unwrap();
+        assert_eq!(handle.join().unwrap(), Some("HelloCounter 1 1\n".to_owned()));
+    }
+
+    #[test]
+    pub fn it_should_send_metrics_configured_with_the_builder() {
+        let server = certificate("server-builder");
+        let (port, handle) = serve(&server, None);
+
+        let configuration = local_test_configuration(port, Protocol::Graphite(Compression::Uncompressed), NetworkProtocol::TLS)
+            .tls(TlsOptions::new().ca_file(server.0.clone()).server_name("localhost".to_owned()))
+            .build()
+            .unwrap();
+        let client = MetricsClient::new(configuration);
+
+        let mut counter = client.counter("HelloCounter".to_owned());
+        counter.increment(1);
+        drop(counter);
+        client.flush();
+
+        let line = handle.join().unwrap().unwrap();
+        assert!(line.starts_with("HelloCounter 1 "), "{}", line);
+    }
+
+    #[test]
+    pub fn it_should_reject_certificates_for_other_names() {
+        let server = certificate("server-name");
+        let (port, handle) = serve(&server, None);
+
+        // The certificate is only valid for localhost, not 127.0.0.1
+        let options = TlsOptions::new().ca_file(server.0.clone());
+
+        assert!(connection(port, options).send(b"HelloCounter 1 1\n").is_err());
+        assert_eq!(handle.join().unwrap(), None);
+    }
+
+    #[test]
+    pub fn it_should_reject_untrusted_servers() {
+        let server = certificate("server-untrusted");
+        let (port, handle) = serve(&server, None);
+
+        let options = TlsOptions::new().server_name("localhost".to_owned());
+
+        assert!(connection(port, options).send(b"HelloCounter 1 1\n").is_err());
+        assert_eq!(handle.join().unwrap(), None);
+    }
+
+    #[test]
+    pub fn it_should_authenticate_with_a_client_certificate() {
+        let server = certificate("server-client-auth");
+        let client = certificate("client");
+        let (port, handle) = serve(&server, Some(&client.0));
+
+        let options = TlsOptions::new()
+            .ca_file(server.0.clone())
+            .server_name("localhost".to_owned())
+            .client_certificate(client.0.clone(), client.1.clone());
+
+        connection(port, options).send(b"HelloCounter 1 1\n").unwrap();
+        assert_eq!(handle.join().unwrap(), Some("HelloCounter 1 1\n".to_owned()));
+    }
+
+    #[test]
+    pub fn it_should_reject_missing_files() {
+        let options = TlsOptions::new().ca_file(PathBuf::from("/nonexistent/ca.pem"));
+        assert!(options.connector("localhost").is_err());
+
+        let options = TlsOptions::new().client_certificate(
+            PathBuf::from("/nonexistent/client.pem"), PathBuf::from("/nonexistent/client.key")
+        );
+        assert!(options.connector("localhost").is_err());
+    }
+}
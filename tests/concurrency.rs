@@ -0,0 +1,144 @@
+use metrical::{counter, flush, from_config, gauge, ConfigurationBuilder, Protocol, NetworkProtocol};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, TcpListener};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const THREADS: usize = 16;
+const METRICS_PER_THREAD: usize = 1000;
+
+static RECEIVED: OnceLock<Arc<Mutex<String>>> = OnceLock::new();
+
+/// Configures metrical once for every test in this file, sending StatsD over TCP to a local
+/// listener which records everything it receives.
+fn received() -> Arc<Mutex<String>> {
+    RECEIVED.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Unable to bind listener");
+        let port = listener.local_addr().unwrap().port();
+        let received = Arc::new(Mutex::new(String::new()));
+
+        let sink = received.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.expect("Unable to accept connection");
+                let sink = sink.clone();
+                thread::spawn(move || {
+                    let mut buf = [0; 4096];
+                    while let Ok(read) = stream.read(&mut buf) {
+                        if read == 0 {
+                            break;
+                        }
+                        sink.lock().unwrap().push_str(&String::from_utf8_lossy(&buf[..read]));
+                    }
+                });
+            }
+        });
+
+        let configuration = ConfigurationBuilder::new()
+            .ip_addr(IpAddr::V4(Ipv4Addr::LOCALHOST))
+            .port(port)
+            .protocol(Protocol::StatsD)
+            .network_protocol(NetworkProtocol::TCP)
+            .flush_after_amount(Some(50))
+            .build()
+            .expect("Unable to build configuration");
+
+        from_config(configuration).expect("Unable to configure metrical");
+
+        received
+    }).clone()
+}
+
+/// Waits until `expected` lines of the series of `record` have been received, asserting
+/// each one is exactly `record`, so records run together by broken framing are caught.
+fn wait_for(received: &Arc<Mutex<String>>, record: &str, expected: usize) -> usize {
+    let series = format!("{}:", record.split(':').next().unwrap());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        flush();
+        let count = {
+            let received = received.lock().unwrap();
+            // A line still being received is only counted once it is complete
+            let complete = &received[..received.rfind('\n').map_or(0, |end| end + 1)];
+
+            let lines: Vec<&str> = complete.split_terminator('\n')
+                .filter(|line| line.contains(&series))
+                .collect();
+            for line in &lines {
+                assert_eq!(*line, record);
+            }
+            lines.len()
+        };
+
+        if count >= expected || Instant::now() > deadline {
+            return count;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+#[test]
+pub fn it_should_not_lose_counters_sent_from_many_threads() {
+    let received = received();
+
+    let handles: Vec<_> = (0..THREADS).map(|_| thread::spawn(|| {
+        for _ in 0..METRICS_PER_THREAD {
+            let mut ctr = counter("ConcurrentCounter".to_owned());
+            ctr.increment(1);
+        }
+    })).collect();
+
+    for handle in handles {
+        handle.join().expect("Counter thread panicked");
+    }
+
+    let expected = THREADS * METRICS_PER_THREAD;
+    assert_eq!(wait_for(&received, "ConcurrentCounter:1|c", expected), expected);
+}
+
+#[test]
+pub fn it_should_not_lose_gauges_sent_from_many_threads() {
+    let received = received();
+
+    let handles: Vec<_> = (0..THREADS).map(|_| thread::spawn(|| {
+        for _ in 0..METRICS_PER_THREAD {
+            let mut gauge = gauge("ConcurrentGauge".to_owned());
+            gauge.increment(1);
+        }
+    })).collect();
+
+    for handle in handles {
+        handle.join().expect("Gauge thread panicked");
+    }
+
+    let expected = THREADS * METRICS_PER_THREAD;
+    assert_eq!(wait_for(&received, "ConcurrentGauge:+1|g", expected), expected);
+}
+
+#[test]
+pub fn it_should_not_lose_counters_while_flushing_concurrently() {
+    let received = received();
+
+    let flusher = thread::spawn(|| {
+        for _ in 0..200 {
+            flush();
+            thread::yield_now();
+        }
+    });
+
+    let handles: Vec<_> = (0..THREADS).map(|_| thread::spawn(|| {
+        for _ in 0..METRICS_PER_THREAD {
+            let mut ctr = counter("FlushedCounter".to_owned());
+            ctr.increment(1);
+        }
+    })).collect();
+
+    for handle in handles {
+        handle.join().expect("Counter thread panicked");
+    }
+    flusher.join().expect("Flush thread panicked");
+
+    let expected = THREADS * METRICS_PER_THREAD;
+    assert_eq!(wait_for(&received, "FlushedCounter:1|c", expected), expected);
+}
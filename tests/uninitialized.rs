@@ -0,0 +1,24 @@
+use metrical::{counter, gauge, timer, namespace, flush, stats, Stats};
+
+#[test]
+pub fn it_should_discard_metrics_recorded_before_initialization() {
+    let mut ctr = counter("HelloCounter".to_owned());
+    ctr.increment(10);
+    drop(ctr);
+
+    let mut gauge = gauge("HelloGauge".to_owned());
+    gauge.increment(5);
+    gauge.set(3);
+    drop(gauge);
+
+    let timer = timer("HelloTimer".to_owned());
+    drop(timer);
+
+    let namespace = namespace("bar".to_owned());
+    let mut ctr = namespace.counter("baz".to_owned());
+    ctr.increment(1);
+    drop(ctr);
+
+    flush();
+    assert_eq!(stats(), Stats::default());
+}
@@ -1,8 +1,18 @@
 use metrical::{counter, gauge, timer, from_env, flush};
 use std::time::Duration;
 
+/// Sets an environment variable unless the caller already provided one.
+fn default_var(key: &str, value: &str) {
+    if std::env::var(key).is_err() {
+        std::env::set_var(key, value);
+    }
+}
+
 #[test]
 pub fn it_should_allow_configuration_from_the_environment() -> Result<(), Box<dyn std::error::Error>> {
+    default_var("METRICAL_NETWORK_PROTOCOL", "UDP");
+    default_var("METRICAL_SEND_METHOD", "StatsD");
+    default_var("METRICAL_NETWORK_DESTINATION", "127.0.0.1:8125");
 
     from_env(None)?;
 